@@ -2,7 +2,7 @@ use std::sync::Arc;
 use crate::geometry::vector::Vector;
 use crate::scene::materials::{DiffuseMaterial};
 use crate::scene::elements::{SceneElement, Triangle};
-use crate::utils::reader::read_obj;
+use crate::utils::reader::{read_obj, ObjError};
 
 // Image settings
 pub const ASPECT_RATIO: f64 = 16./9.;
@@ -21,29 +21,156 @@ pub const VOID: Vector = Vector::new(0., 0., 0.0);
 pub const RAYS_PER_PIXEL: u32 = 200;
 pub const BOUNCES: u8 = 4;
 pub const EPSILON: f64 = 1e-6;
+// Camera rays are traced in packets of PACKET_SIZE x PACKET_SIZE pixels, at
+// most 8 since packets hold up to 64 rays
+pub const PACKET_SIZE: u32 = 8;
+const _: () = assert!(PACKET_SIZE * PACKET_SIZE <= 64);
 
-pub fn create_scene() -> Vec<Arc<dyn SceneElement>> {
-    let mut elements = read_obj("src/assets/shell.obj", Arc::new(DiffuseMaterial::new(
-        Vector::new(0.6, 0.4, 0.05), 0.
-    )));
+// BVH settings. Costs are relative to each other, traversal is testing a ray
+// against a node's hitbox and intersection against one of its elements
+pub const TRAVERSAL_COST: f64 = 1.;
+pub const INTERSECTION_COST: f64 = 1.;
+pub const MAX_LEAF_SIZE: usize = 8;
+pub const SPATIAL_SPLITS: bool = false;
+pub const MAX_SPLIT_GROWTH: f64 = 1.;
+pub const REBUILD_THRESHOLD: f64 = 1.5;
+// Number of bins the surface area heuristic sorts items into along each axis
+pub const SAH_BINS: usize = 16;
+// Number of bins spatial splits are tried between along each axis
+pub const SPATIAL_BINS: usize = 16;
+// Spatial splits are only tried where the two sides of the best object split
+// overlap by more than this fraction of the surface area of the whole BVH
+pub const MIN_OVERLAP: f64 = 1e-5;
+// Subtrees with at least this many items are built in parallel
+pub const PARALLEL_BUILD_SIZE: usize = 4096;
 
-    let mut snail = read_obj("src/assets/snail.obj", Arc::new(DiffuseMaterial::new(
-        Vector::new(1., 0.71, 0.), 0.
-    )));
+// Mesh settings. Faces of .stl files that meet at a sharper angle than this,
+// in degrees, keep a hard edge between them instead of being shaded smoothly
+pub const STL_SMOOTHING_ANGLE: f64 = 30.;
 
-    elements.append(&mut snail);
+// Settings of a rendered image. They default to the constants above
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub rays_per_pixel: u32,
+    pub bounces: u8,
+    // Color of rays that don't hit anything
+    pub void: Vector,
+    // When set, every pixel is rendered deterministically
+    pub seed: Option<u64>
+}
 
-    let mut rocks = read_obj("src/assets/rocks.obj", Arc::new(DiffuseMaterial::new(
-        Vector::new(0.62, 0.62, 0.62), 0.
-    )));
+impl RenderSettings {
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
 
-    elements.append(&mut rocks);
+    // Changes the image size. If only one of the dimensions is given, the
+    // other one is derived from the current aspect ratio. Dimensions must not
+    // be 0
+    pub fn resize(&mut self, width: Option<u32>, height: Option<u32>) {
+        match (width, height) {
+            (Some(width), Some(height)) => {
+                self.width = width;
+                self.height = height;
+            },
+            (Some(width), None) => {
+                self.height = ((width as f64 / self.aspect_ratio()) as u32).max(1);
+                self.width = width;
+            },
+            (None, Some(height)) => {
+                self.width = ((height as f64 * self.aspect_ratio()) as u32).max(1);
+                self.height = height;
+            },
+            (None, None) => ()
+        }
+    }
+}
 
-    let mut grass = read_obj("src/assets/grass.obj", Arc::new(DiffuseMaterial::new(
-        Vector::new(0., 0.9, 0.06), 0.
-    )));
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: WIDTH,
+            height: HEIGHT,
+            rays_per_pixel: RAYS_PER_PIXEL,
+            bounces: BOUNCES,
+            void: VOID,
+            seed: None
+        }
+    }
+}
+
+// Position and lens of the camera. They default to the constants above
+#[derive(Debug, Clone, Copy)]
+pub struct CameraSettings {
+    pub position: Vector,
+    pub look_at: Vector,
+    pub view_up: Vector,
+    pub fov_angle: f64,
+    pub defocus_angle: f64
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            position: CAMERA_POSITION,
+            look_at: LOOK_AT,
+            view_up: VIEW_UP,
+            fov_angle: FOV_ANGLE,
+            defocus_angle: DEFOCUS_ANGLE
+        }
+    }
+}
+
+// Cost model of the surface area heuristic used to build BVHs. They default to
+// the constants above
+#[derive(Debug, Clone, Copy)]
+pub struct BvhSettings {
+    pub traversal_cost: f64,
+    pub intersection_cost: f64,
+    // Leaves are only made when they are cheaper than any split, and never
+    // hold more than this many elements unless they can't be split
+    pub max_leaf_size: usize,
+    // Whether items that straddle a split can be split in two, which helps
+    // with long, thin triangles at the cost of more memory
+    pub spatial_splits: bool,
+    // How many more references to items than items spatial splits can add,
+    // as a fraction of the number of items
+    pub max_split_growth: f64,
+    // Refitted BVHs are built again once their SAH cost is this many times
+    // the cost they were built with
+    pub rebuild_threshold: f64
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self {
+            traversal_cost: TRAVERSAL_COST,
+            intersection_cost: INTERSECTION_COST,
+            max_leaf_size: MAX_LEAF_SIZE,
+            spatial_splits: SPATIAL_SPLITS,
+            max_split_growth: MAX_SPLIT_GROWTH,
+            rebuild_threshold: REBUILD_THRESHOLD
+        }
+    }
+}
 
-    elements.append(&mut grass);
+pub fn create_scene() -> Result<Vec<Arc<dyn SceneElement>>, ObjError> {
+    let mut elements: Vec<Arc<dyn SceneElement>> = vec![
+        Arc::new(read_obj("src/assets/shell.obj", Arc::new(DiffuseMaterial::new(
+            Vector::new(0.6, 0.4, 0.05), 0.
+        )))?),
+        Arc::new(read_obj("src/assets/snail.obj", Arc::new(DiffuseMaterial::new(
+            Vector::new(1., 0.71, 0.), 0.
+        )))?),
+        Arc::new(read_obj("src/assets/rocks.obj", Arc::new(DiffuseMaterial::new(
+            Vector::new(0.62, 0.62, 0.62), 0.
+        )))?),
+        Arc::new(read_obj("src/assets/grass.obj", Arc::new(DiffuseMaterial::new(
+            Vector::new(0., 0.9, 0.06), 0.
+        )))?)
+    ];
 
     // Cornell box
     elements.push(Arc::new(Triangle::new(
@@ -133,5 +260,5 @@ pub fn create_scene() -> Vec<Arc<dyn SceneElement>> {
         Arc::new(DiffuseMaterial::new(Vector::new(1., 1., 1.), 3.))
     )));
     
-    elements
+    Ok(elements)
 }
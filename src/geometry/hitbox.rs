@@ -21,11 +21,23 @@ impl HitBox {
         }
     }
 
+    pub const fn start(&self) -> Vector {
+        self.start
+    }
+
+    pub const fn end(&self) -> Vector {
+        self.end
+    }
+
+    pub fn center(&self) -> Vector {
+        (self.start + self.end) / 2.
+    }
+
     pub const fn area(&self) -> f64 {
         let width = self.end.x - self.start.x;
         let height = self.end.y - self.start.y;
         let depth = self.end.z - self.start.z;
-        width * height * depth
+        2. * (width * height + height * depth + depth * width)
     }
 
     pub fn merge(&mut self, other: &HitBox) {
@@ -38,6 +50,13 @@ impl HitBox {
         self.end.z = self.end.z.max(other.end.z);
     }
     
+    // The box where both hitboxes overlap, if they do
+    pub fn intersection(&self, other: &HitBox) -> Option<HitBox> {
+        let start = Vector::new(self.start.x.max(other.start.x), self.start.y.max(other.start.y), self.start.z.max(other.start.z));
+        let end = Vector::new(self.end.x.min(other.end.x), self.end.y.min(other.end.y), self.end.z.min(other.end.z));
+        (start.x <= end.x && start.y <= end.y && start.z <= end.z).then(|| HitBox::new(start, end))
+    }
+
     // Compares the center of two hitboxes along a given axis
     pub fn compare(&self, other: &HitBox, axis: Axis) -> Ordering {
         match axis {
@@ -1,3 +1,4 @@
 pub mod vector;
 pub mod ray;
 pub mod hitbox;
+pub mod transform;
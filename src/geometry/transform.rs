@@ -0,0 +1,258 @@
+use std::ops::Mul;
+use crate::geometry::vector::Vector;
+use crate::geometry::ray::Ray;
+use crate::geometry::hitbox::HitBox;
+
+type Matrix = [[f64; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1., 0., 0., 0.],
+    [0., 1., 0., 0.],
+    [0., 0., 1., 0.],
+    [0., 0., 0., 1.]
+];
+
+// An affine transform, stored as a 4x4 matrix together with its inverse
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    matrix: Matrix,
+    inverse: Matrix
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { matrix: IDENTITY, inverse: IDENTITY };
+
+    // Returns None if the matrix can't be inverted
+    pub fn new(matrix: [[f64; 4]; 4]) -> Option<Self> {
+        let inverse = invert(&matrix)?;
+        Some(Self { matrix, inverse })
+    }
+
+    pub fn translation(offset: Vector) -> Self {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        matrix[0][3] = offset.x;
+        matrix[1][3] = offset.y;
+        matrix[2][3] = offset.z;
+        inverse[0][3] = -offset.x;
+        inverse[1][3] = -offset.y;
+        inverse[2][3] = -offset.z;
+        Self { matrix, inverse }
+    }
+
+    // Scales each axis by the corresponding component of factors, which must
+    // not be zero
+    pub fn scaling(factors: Vector) -> Self {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        matrix[0][0] = factors.x;
+        matrix[1][1] = factors.y;
+        matrix[2][2] = factors.z;
+        inverse[0][0] = 1. / factors.x;
+        inverse[1][1] = 1. / factors.y;
+        inverse[2][2] = 1. / factors.z;
+        Self { matrix, inverse }
+    }
+
+    // Rotates counter-clockwise around axis by angle radians, following the
+    // right-hand rule
+    // https://en.wikipedia.org/wiki/Rotation_matrix#Rotation_matrix_from_axis_and_angle
+    pub fn rotation(axis: Vector, angle: f64) -> Self {
+        let u = axis.normalize();
+        let (sin, cos) = angle.sin_cos();
+        let t = 1. - cos;
+
+        let matrix = [
+            [cos + u.x * u.x * t, u.x * u.y * t - u.z * sin, u.x * u.z * t + u.y * sin, 0.],
+            [u.y * u.x * t + u.z * sin, cos + u.y * u.y * t, u.y * u.z * t - u.x * sin, 0.],
+            [u.z * u.x * t - u.y * sin, u.z * u.y * t + u.x * sin, cos + u.z * u.z * t, 0.],
+            [0., 0., 0., 1.]
+        ];
+
+        // The inverse of a rotation is its transpose
+        Self { matrix, inverse: transpose(&matrix) }
+    }
+
+    pub fn rotation_x(angle: f64) -> Self {
+        Self::rotation(Vector::new(1., 0., 0.), angle)
+    }
+
+    pub fn rotation_y(angle: f64) -> Self {
+        Self::rotation(Vector::new(0., 1., 0.), angle)
+    }
+
+    pub fn rotation_z(angle: f64) -> Self {
+        Self::rotation(Vector::new(0., 0., 1.), angle)
+    }
+
+    // Returns the transform that applies self and then other
+    pub fn then(&self, other: &Transform) -> Transform {
+        *other * *self
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform { matrix: self.inverse, inverse: self.matrix }
+    }
+
+    pub fn matrix(&self) -> &[[f64; 4]; 4] {
+        &self.matrix
+    }
+
+    // Transforms with a negative determinant mirror the space, which flips the
+    // winding order of triangles
+    pub fn flips_orientation(&self) -> bool {
+        let m = &self.matrix;
+        let determinant = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        determinant < 0.
+    }
+
+    pub fn point(&self, point: &Vector) -> Vector {
+        let m = &self.matrix;
+        Vector::new(
+            m[0][0] * point.x + m[0][1] * point.y + m[0][2] * point.z + m[0][3],
+            m[1][0] * point.x + m[1][1] * point.y + m[1][2] * point.z + m[1][3],
+            m[2][0] * point.x + m[2][1] * point.y + m[2][2] * point.z + m[2][3]
+        )
+    }
+
+    // Directions are not affected by translations
+    pub fn vector(&self, vector: &Vector) -> Vector {
+        let m = &self.matrix;
+        Vector::new(
+            m[0][0] * vector.x + m[0][1] * vector.y + m[0][2] * vector.z,
+            m[1][0] * vector.x + m[1][1] * vector.y + m[1][2] * vector.z,
+            m[2][0] * vector.x + m[2][1] * vector.y + m[2][2] * vector.z
+        )
+    }
+
+    // Normals are transformed by the inverse transpose of the matrix, so that
+    // they stay perpendicular to the surface under non-uniform scaling. The
+    // result is normalized
+    pub fn normal(&self, normal: &Vector) -> Vector {
+        let m = &self.inverse;
+        Vector::new(
+            m[0][0] * normal.x + m[1][0] * normal.y + m[2][0] * normal.z,
+            m[0][1] * normal.x + m[1][1] * normal.y + m[2][1] * normal.z,
+            m[0][2] * normal.x + m[1][2] * normal.y + m[2][2] * normal.z
+        ).normalize()
+    }
+
+    // The direction is not normalized, so that distances along the
+    // transformed ray match distances along the original one
+    pub fn ray(&self, ray: &Ray) -> Ray {
+        Ray::new(self.point(&ray.origin), self.vector(&ray.direction))
+    }
+
+    // Moves a ray back from the transformed space, without building the
+    // inverse transform
+    pub fn inverse_ray(&self, ray: &Ray) -> Ray {
+        let m = &self.inverse;
+        let (o, d) = (&ray.origin, &ray.direction);
+        Ray::new(
+            Vector::new(
+                m[0][0] * o.x + m[0][1] * o.y + m[0][2] * o.z + m[0][3],
+                m[1][0] * o.x + m[1][1] * o.y + m[1][2] * o.z + m[1][3],
+                m[2][0] * o.x + m[2][1] * o.y + m[2][2] * o.z + m[2][3]
+            ),
+            Vector::new(
+                m[0][0] * d.x + m[0][1] * d.y + m[0][2] * d.z,
+                m[1][0] * d.x + m[1][1] * d.y + m[1][2] * d.z,
+                m[2][0] * d.x + m[2][1] * d.y + m[2][2] * d.z
+            )
+        )
+    }
+
+    // Returns a hitbox that contains the transformed corners of hitbox
+    pub fn hitbox(&self, hitbox: &HitBox) -> HitBox {
+        let (start, end) = (hitbox.start(), hitbox.end());
+        let mut result: Option<HitBox> = None;
+
+        for corner in 0..8 {
+            let point = self.point(&Vector::new(
+                if corner & 1 == 0 { start.x } else { end.x },
+                if corner & 2 == 0 { start.y } else { end.y },
+                if corner & 4 == 0 { start.z } else { end.z }
+            ));
+            let corner = HitBox::new(point, point);
+            match &mut result {
+                Some(result) => result.merge(&corner),
+                None => result = Some(corner)
+            }
+        }
+
+        result.unwrap()
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+// Matrix composition: (a * b) applies b first and then a
+impl Mul<Transform> for Transform {
+    type Output = Transform;
+
+    fn mul(self, other: Transform) -> Transform {
+        Transform {
+            matrix: multiply(&self.matrix, &other.matrix),
+            inverse: multiply(&other.inverse, &self.inverse)
+        }
+    }
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.; 4]; 4];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    let mut result = [[0.; 4]; 4];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = m[j][i];
+        }
+    }
+    result
+}
+
+// Gauss-Jordan elimination with partial pivoting
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let mut a = *m;
+    let mut result = IDENTITY;
+
+    for column in 0..4 {
+        let pivot = (column..4)
+            .max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))
+            .unwrap();
+        if a[pivot][column].abs() < 1e-12 { return None }
+
+        a.swap(column, pivot);
+        result.swap(column, pivot);
+
+        let factor = a[column][column];
+        for j in 0..4 {
+            a[column][j] /= factor;
+            result[column][j] /= factor;
+        }
+
+        for i in 0..4 {
+            if i == column { continue }
+            let factor = a[i][column];
+            for j in 0..4 {
+                a[i][j] -= factor * a[column][j];
+                result[i][j] -= factor * result[column][j];
+            }
+        }
+    }
+
+    Some(result)
+}
@@ -1,7 +1,7 @@
 use std::ops::{Neg, Add, Sub, Mul, Div, AddAssign, SubAssign, MulAssign, DivAssign};
 use std::fmt;
-use rand::random;
 use crate::config::EPSILON;
+use crate::utils::utils::random;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Vector {
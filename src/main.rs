@@ -1,39 +1,285 @@
-use image::{RgbImage, Rgb};
-use rayon::prelude::*;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use caracol::renderer::raytracer::Raytracer;
-use caracol::utils::utils::{self, to_rgb};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clap::{Args, Parser, Subcommand};
+use caracol::renderer::raytracer::{Raytracer, Traversal};
+use caracol::scene::bvh::BvhStats;
+use caracol::scene::cache::set_cache_directory;
+use caracol::scene::elements::{ElementVisitor, Placement, SceneElement};
+use caracol::scene::tlas::Tlas;
+use caracol::scene::materials::Material;
+use caracol::scene::mesh::Mesh;
+use caracol::geometry::vector::Vector;
+use caracol::scene::loader::{load_scene, Scene};
+use caracol::utils::utils;
+use caracol::utils::writer::{write_obj, write_scene};
 use caracol::config::*;
 
+#[derive(Parser)]
+#[command(version, about = "My very own slow raytracer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a scene and save it as an image
+    Render {
+        #[command(flatten)]
+        options: Options,
+        /// Path of the rendered image
+        #[arg(short, long, default_value = "output.png")]
+        output: PathBuf
+    },
+    /// Print the settings and statistics of a scene
+    Info {
+        #[command(flatten)]
+        options: Options
+    },
+    /// Render a scene several times and report how long it takes
+    Bench {
+        #[command(flatten)]
+        options: Options,
+        /// Number of times the scene is rendered
+        #[arg(short, long, default_value_t = 3)]
+        runs: u32
+    },
+    /// Write a scene to an .obj file or to a scene file
+    Export {
+        #[command(flatten)]
+        options: Options,
+        /// Path of the exported file. .obj files get their materials in an
+        /// .mtl file next to them, anything else is written as a scene file
+        #[arg(short, long)]
+        output: PathBuf
+    }
+}
+
+#[derive(Args, Default)]
+struct Options {
+    /// Scene file to load. The scene in config.rs is used if none is given
+    #[arg(long)]
+    scene: Option<PathBuf>,
+    /// Image width. Keeps the aspect ratio if no height is given
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+    /// Image height. Keeps the aspect ratio if no width is given
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+    /// Rays traced per pixel
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    samples: Option<u32>,
+    /// Maximum number of bounces per ray
+    #[arg(long)]
+    bounces: Option<u8>,
+    /// Seed for reproducible renders
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Number of threads. Defaults to one per core
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+    /// Always read meshes from their files instead of the mesh cache
+    #[arg(long)]
+    no_cache: bool,
+    /// Directory of the mesh cache. Defaults to .caracol-cache
+    #[arg(long)]
+    cache_dir: Option<PathBuf>
+}
+
 fn main() {
-    let raytracer = Raytracer::new(create_scene());
-    
-    let progress = AtomicUsize::new(0);
-    utils::print_progress(0.);
-    
-    // Render the image in parallel
-    let pixels: Vec<(u32, u32, Rgb<u8>)> = (0..WIDTH).into_par_iter().flat_map(|x| -> Vec<(u32, u32, Rgb<u8>)> {
-        let result = (0..HEIGHT).map(|y| {
-            let color = raytracer.pixel_color(x, y);
-            (x, y, to_rgb(&color))
-        }).collect();
-
-        let done = progress.fetch_add(1, Ordering::Relaxed);
-        utils::print_progress(done as f64 / WIDTH as f64);
-
-        result
-    }).collect();
-    
-    utils::print_progress(1.);
-    println!("");
-    
-    // Save the image as a .png file
-    let mut image = RgbImage::new(WIDTH, HEIGHT);
-    for (x, y, color) in pixels {
-        image.put_pixel(x, y, color);
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Render {
+        options: Options::default(),
+        output: PathBuf::from("output.png")
+    });
+
+    match command {
+        Command::Render { options, output } => {
+            let scene = setup(&options);
+            let raytracer = Raytracer::new(scene.elements, scene.camera, scene.render);
+
+            utils::print_progress(0.);
+            let image = raytracer.render(utils::print_progress);
+            println!();
+
+            match image.save(&output) {
+                Ok(()) => println!("Image saved"),
+                Err(e) => {
+                    eprintln!("Couldn't save image: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Command::Info { options } => {
+            let start = Instant::now();
+            let Scene { render, camera, elements } = setup(&options);
+            let load_time = start.elapsed();
+            let count = elements.len();
+
+            let mut stats = MeshStats::default();
+            for element in &elements {
+                element.visit(&mut stats, &Placement::default());
+            }
+
+            let start = Instant::now();
+            let bvh = Tlas::new(elements);
+            let build_time = start.elapsed();
+
+            println!("Image:      {}x{}", render.width, render.height);
+            println!("Samples:    {} rays per pixel, {} bounces", render.rays_per_pixel, render.bounces);
+            println!("Camera:     {} looking at {}", camera.position, camera.look_at);
+            println!("FOV:        {:.1}°", camera.fov_angle.to_degrees());
+            println!("Elements:   {}", count);
+            let mut bvh_stats = BvhStats::default();
+            bvh.bvh_stats(&mut bvh_stats, 0);
+            println!("Scene BVH:  {}", describe_bvh(&bvh_stats, bvh_stats.sah_cost(&BvhSettings::default())));
+            println!("Meshes:     {} with {} triangles", stats.meshes.len(), stats.triangles);
+            if !stats.meshes.is_empty() {
+                println!("Mesh BVHs:  {}", describe_bvh(&stats.bvh, stats.cost / stats.triangles.max(1) as f64));
+            }
+            println!("Bounds:     {} to {}", bvh.hitbox().start(), bvh.hitbox().end());
+            println!("Load:       {:.3}s", load_time.as_secs_f64());
+            println!("BVH build:  {:.3}s", build_time.as_secs_f64());
+        },
+        Command::Bench { options, runs } => {
+            let scene = setup(&options);
+            let settings = scene.render;
+
+            let start = Instant::now();
+            let mut raytracer = Raytracer::new(scene.elements, scene.camera, scene.render);
+            println!("BVH build:  {:.3}s", start.elapsed().as_secs_f64());
+
+            // Camera rays are traced on their own and then in packets, to
+            // compare both
+            let rays = settings.width as f64 * settings.height as f64 * settings.rays_per_pixel as f64;
+            let mut speeds = Vec::new();
+            for (traversal, name) in [(Traversal::Single, "Single rays"), (Traversal::Packets, "Packets")] {
+                raytracer.set_traversal(traversal);
+                println!("{}:", name);
+
+                let mut times: Vec<Duration> = Vec::new();
+                for run in 1..=runs.max(1) {
+                    let start = Instant::now();
+                    raytracer.render(|_| ());
+                    let time = start.elapsed();
+                    println!("Run {}:      {:.3}s", run, time.as_secs_f64());
+                    times.push(time);
+                }
+
+                let best = times.iter().min().unwrap().as_secs_f64();
+                let mean = times.iter().sum::<Duration>().as_secs_f64() / times.len() as f64;
+                println!("Best:       {:.3}s", best);
+                println!("Mean:       {:.3}s", mean);
+                println!("Camera rays per second: {:.0}", rays / best);
+                speeds.push(rays / best);
+            }
+            println!("Packets are {:.2}x as fast as single rays", speeds[1] / speeds[0]);
+        },
+        Command::Export { options, output } => {
+            let scene = setup(&options);
+            let filename = output.to_string_lossy();
+            let extension = output.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+            let result = if extension == "obj" {
+                write_obj(&filename, &scene.elements)
+            } else {
+                write_scene(&filename, &scene)
+            };
+
+            match result {
+                Ok(()) => println!("Scene exported"),
+                Err(e) => {
+                    eprintln!("Couldn't export scene: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
-    match image.save("output.png") {
-        Ok(()) => println!("Image saved"),
-        Err(e) => eprintln!("Couldn't save image: {}", e)
+}
+
+// Counts the meshes of a scene and their triangles, and adds up the
+// statistics of their BVHs. Meshes shared by several instances are only
+// counted once
+#[derive(Default)]
+struct MeshStats {
+    meshes: HashSet<*const Mesh>,
+    triangles: usize,
+    bvh: BvhStats,
+    // SAH cost of each mesh weighted by its triangles
+    cost: f64
+}
+
+impl ElementVisitor for MeshStats {
+    fn sphere(&mut self, _: Vector, _: f64, _: &Arc<dyn Material>, _: &Placement) {}
+
+    fn triangle(&mut self, _: [Vector; 3], _: Option<[Vector; 3]>, _: Option<[(f64, f64); 3]>, _: &Arc<dyn Material>, _: &Placement) {}
+
+    fn mesh(&mut self, mesh: &Mesh, _: &Placement) {
+        if !self.meshes.insert(mesh) { return }
+
+        let stats = mesh.bvh_stats();
+        self.triangles += mesh.faces().len();
+        self.cost += stats.sah_cost(&BvhSettings::default()) * mesh.faces().len() as f64;
+        self.bvh.nodes += stats.nodes;
+        self.bvh.leaves += stats.leaves;
+        self.bvh.depth = self.bvh.depth.max(stats.depth);
+        if self.bvh.leaf_sizes.len() < stats.leaf_sizes.len() {
+            self.bvh.leaf_sizes.resize(stats.leaf_sizes.len(), 0);
+        }
+        for (size, count) in stats.leaf_sizes.iter().enumerate() {
+            self.bvh.leaf_sizes[size] += count;
+        }
     }
 }
+
+fn describe_bvh(stats: &BvhStats, cost: f64) -> String {
+    let smallest = stats.leaf_sizes.iter().position(|&count| count > 0).unwrap_or(0);
+    let largest = stats.leaf_sizes.len().saturating_sub(1);
+    format!(
+        "{} nodes, {} levels, {} leaves of {} to {} elements ({:.1} on average), SAH cost {:.2}",
+        stats.nodes, stats.depth, stats.leaves, smallest, largest, stats.mean_leaf_size(), cost
+    )
+}
+
+// Loads the scene, applies the command-line overrides to its settings and
+// configures the thread pool
+fn setup(options: &Options) -> Scene {
+    if !options.no_cache {
+        set_cache_directory(Some(options.cache_dir.clone().unwrap_or_else(|| PathBuf::from(".caracol-cache"))));
+    }
+
+    let mut scene = match &options.scene {
+        Some(path) => match load_scene(path) {
+            Ok(scene) => scene,
+            Err(e) => {
+                eprintln!("Couldn't load scene: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Scene {
+            render: RenderSettings::default(),
+            camera: CameraSettings::default(),
+            elements: match create_scene() {
+                Ok(elements) => elements,
+                Err(e) => {
+                    eprintln!("Couldn't load scene: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    let render = &mut scene.render;
+    render.resize(options.width, options.height);
+    if let Some(samples) = options.samples { render.rays_per_pixel = samples; }
+    if let Some(bounces) = options.bounces { render.bounces = bounces; }
+    if options.seed.is_some() { render.seed = options.seed; }
+
+    if let Some(threads) = options.threads
+        && let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+        eprintln!("Couldn't configure thread pool: {}", e);
+    }
+
+    scene
+}
@@ -1,30 +1,44 @@
-use rand::random;
 use crate::geometry::vector::Vector;
 use crate::geometry::ray::Ray;
-use crate::config::*;
+use crate::config::{CameraSettings, RenderSettings};
+use crate::utils::utils::random;
 
 pub struct Camera {
+    position: Vector,
     forward: Vector,
     right: Vector,
     up: Vector,
     focus_distance: f64,
-    defocus_radius: f64
+    defocus_radius: f64,
+
+    // Half the size of the image plane at distance 1, and the image size
+    half_width: f64,
+    half_height: f64,
+    width: u32,
+    height: u32
 }
 
 impl Camera {
-    pub fn new() -> Self {
-        let forward = (LOOK_AT - CAMERA_POSITION).normalize();
-        let right = forward.cross(&VIEW_UP).normalize();
+    pub fn new(camera: &CameraSettings, render: &RenderSettings) -> Self {
+        let forward = (camera.look_at - camera.position).normalize();
+        let right = forward.cross(&camera.view_up).normalize();
         let up = right.cross(&forward).normalize();
-        let focus_distance = (LOOK_AT - CAMERA_POSITION).magnitude();
-        let defocus_radius = focus_distance * DEFOCUS_ANGLE.sin();
+        let focus_distance = (camera.look_at - camera.position).magnitude();
+        let defocus_radius = focus_distance * camera.defocus_angle.sin();
+        let half_width = (camera.fov_angle / 2.).tan();
+        let half_height = half_width / render.aspect_ratio();
 
         Self {
+            position: camera.position,
             forward,
             right,
             up,
             focus_distance,
-            defocus_radius
+            defocus_radius,
+            half_width,
+            half_height,
+            width: render.width,
+            height: render.height
         }
     }
 
@@ -47,19 +61,19 @@ impl Camera {
         );
 
         // Calculating the end position. Top left corner is given by
-        // (-tan(FOV_ANGLE / 2), tan(FOV_ANGLE / 2) / ASPECT_RATIO)
+        // (-tan(fov_angle / 2), tan(fov_angle / 2) / aspect_ratio)
         let offset_x = random::<f64>() - 0.5;
         let offset_y = random::<f64>() - 0.5;
         let end = Vector::new(
-            (FOV_ANGLE / 2.).tan() * (-1. + (x as f64 + 0.5 + offset_x) * 2. / WIDTH as f64),
-            (FOV_ANGLE / 2.).tan() / ASPECT_RATIO * (1. - (y as f64 + 0.5 + offset_y) * 2. / HEIGHT as f64),
+            self.half_width * (-1. + (x as f64 + 0.5 + offset_x) * 2. / self.width as f64),
+            self.half_height * (1. - (y as f64 + 0.5 + offset_y) * 2. / self.height as f64),
             - 1.
         ) * self.focus_distance;
 
         let direction = end - start;
 
         // Transform the ray's coordinates from camera space to world space
-        let world_position = CAMERA_POSITION + start.x * self.right + start.y * self.up;
+        let world_position = self.position + start.x * self.right + start.y * self.up;
         let world_direction = direction.x * self.right + direction.y * self.up - direction.z * self.forward;
         Ray::new(world_position, world_direction.normalize())
     }
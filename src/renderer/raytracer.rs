@@ -1,57 +1,182 @@
+use std::ops::Range;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use image::{Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rayon::prelude::*;
 use crate::geometry::ray::Ray;
 use crate::geometry::vector::Vector;
-use crate::scene::elements::SceneElement;
-use crate::scene::bvh::build_bvh;
+use crate::scene::elements::{CollisionInfo, SceneElement};
+use crate::scene::tlas::Tlas;
 use crate::renderer::camera::Camera;
-use crate::config::*;
+use crate::config::{CameraSettings, RenderSettings, EPSILON, PACKET_SIZE};
+use crate::utils::utils::{new_random, seed_random, to_rgb, with_random};
+
+// How camera rays are traced through the scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    // Every ray on its own
+    Single,
+    // Camera rays in packets of neighbouring pixels, and then every bounce on
+    // its own since bounced rays go in all directions
+    Packets
+}
 
 pub struct Raytracer {
     camera: Camera,
-    bvh: Arc<dyn SceneElement>
-
+    settings: RenderSettings,
+    scene: Tlas,
+    traversal: Traversal
 }
 
 impl Raytracer {
-    pub fn new(scene: Vec<Arc<dyn SceneElement>>) -> Self {
+    pub fn new(scene: Vec<Arc<dyn SceneElement>>, camera: CameraSettings, settings: RenderSettings) -> Self {
         Self {
-            camera: Camera::new(),
-            bvh: build_bvh(scene)
+            camera: Camera::new(&camera, &settings),
+            settings,
+            scene: Tlas::new(scene),
+            traversal: Traversal::Packets
+        }
+    }
+
+    pub fn traversal(&self) -> Traversal {
+        self.traversal
+    }
+
+    // Both traversals render the same image, but with different speeds
+    pub fn set_traversal(&mut self, traversal: Traversal) {
+        self.traversal = traversal;
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    // The objects of the scene, which can be added, removed and moved between
+    // renders
+    pub fn scene(&self) -> &Tlas {
+        &self.scene
+    }
+
+    pub fn scene_mut(&mut self) -> &mut Tlas {
+        &mut self.scene
+    }
+
+    // Renders the whole image in parallel. progress is called with the
+    // fraction of the image that is done every time a column is finished, or
+    // a column of packets
+    pub fn render(&self, progress: impl Fn(f64) + Sync) -> RgbImage {
+        let (width, height) = (self.settings.width, self.settings.height);
+        let done = AtomicUsize::new(0);
+        let strip_width = match self.traversal {
+            Traversal::Single => 1,
+            Traversal::Packets => PACKET_SIZE
+        };
+
+        let pixels: Vec<(u32, u32, Rgb<u8>)> = (0..width.div_ceil(strip_width)).into_par_iter().flat_map(|strip| -> Vec<(u32, u32, Rgb<u8>)> {
+            let columns = strip * strip_width..((strip + 1) * strip_width).min(width);
+            let result = match self.traversal {
+                Traversal::Single => columns.clone().flat_map(|x| (0..height).map(move |y| (x, y, to_rgb(&self.pixel_color(x, y))))).collect(),
+                Traversal::Packets => (0..height).step_by(PACKET_SIZE as usize).flat_map(|y| {
+                    self.tile_colors(columns.clone(), y..(y + PACKET_SIZE).min(height))
+                }).collect()
+            };
+
+            let columns = done.fetch_add(columns.len(), Ordering::Relaxed) + columns.len();
+            progress(columns as f64 / width as f64);
+
+            result
+        }).collect();
+
+        let mut image = RgbImage::new(width, height);
+        for (x, y, color) in pixels {
+            image.put_pixel(x, y, color);
         }
+        image
     }
 
     pub fn pixel_color(&self, x: u32, y: u32) -> Vector {
+        let settings = &self.settings;
         let mut result = Vector::ZERO;
+
+        // Seeding each pixel on its own keeps the result independent of the
+        // order in which threads render them
+        if let Some(seed) = settings.seed {
+            seed_random(pixel_seed(seed, x, y));
+        }
     
-        for _ in 0..RAYS_PER_PIXEL {
-            result += self.raytrace(&self.camera.ray(x, y), BOUNCES);
+        for _ in 0..settings.rays_per_pixel {
+            result += self.raytrace(&self.camera.ray(x, y), settings.bounces);
         }
     
-        result /= RAYS_PER_PIXEL as f64;
+        result /= settings.rays_per_pixel as f64;
         result.clamp(0., 1.)
     }
+
+    // Colors a tile of pixels like pixel_color, tracing the camera rays of
+    // each sample as a packet. Each pixel gets its own random generator, so
+    // that it gets the same random values as with pixel_color
+    fn tile_colors(&self, columns: Range<u32>, rows: Range<u32>) -> Vec<(u32, u32, Rgb<u8>)> {
+        let settings = &self.settings;
+        let pixels: Vec<(u32, u32)> = rows.flat_map(|y| columns.clone().map(move |x| (x, y))).collect();
+        let mut generators: Vec<StdRng> = pixels.iter().map(|&(x, y)| new_random(settings.seed.map(|seed| pixel_seed(seed, x, y)))).collect();
+        let mask = u64::MAX >> (64 - pixels.len());
+        let mut results = vec![Vector::ZERO; pixels.len()];
+
+        for _ in 0..settings.rays_per_pixel {
+            let rays: Vec<Ray> = pixels.iter().zip(&mut generators)
+                .map(|(&(x, y), generator)| with_random(generator, || self.camera.ray(x, y)))
+                .collect();
+            let mut closest = vec![f64::INFINITY; rays.len()];
+            let mut hits: Vec<Option<CollisionInfo>> = rays.iter().map(|_| None).collect();
+            self.scene.collide_packet(&rays, mask, &mut closest, &mut hits);
+
+            for (i, hit) in hits.into_iter().enumerate() {
+                results[i] += with_random(&mut generators[i], || self.shade(&rays[i], hit, settings.bounces));
+            }
+        }
+
+        pixels.into_iter().zip(results).map(|((x, y), mut result)| {
+            result /= settings.rays_per_pixel as f64;
+            (x, y, to_rgb(&result.clamp(0., 1.)))
+        }).collect()
+    }
     
     // Returns the color of the ray, using diffuse reflection
     fn raytrace(&self, ray: &Ray, bounces: u8) -> Vector {
-    
         // Find the closest collision
-        let collision = self.bvh.collide(ray, f64::INFINITY);
-    
-        if collision.is_none() { return VOID }
-        let info = collision.unwrap();
+        let collision = self.scene.collide(ray, f64::INFINITY);
+        self.shade(ray, collision, bounces)
+    }
+
+    // Returns the color of a ray given its closest collision
+    fn shade(&self, ray: &Ray, collision: Option<CollisionInfo>, bounces: u8) -> Vector {
+        let Some(info) = collision else { return self.settings.void };
     
         // Compute the pixel's color
     
-        if bounces == 0 { return info.material.emission() };
+        if bounces == 0 { return info.material.emission(&info) };
     
         // Adding normal * EPSILON helps to prevent shadow acne
-        let intersection = ray.at(info.distance) + info.normal * EPSILON;
+        let intersection = ray.at(info.distance) + info.geometric_normal * EPSILON;
     
-        let emitted = info.material.emission();
-        let reflected_ray = &info.material.reflect(&ray, &intersection, &info.normal);
-        let reflected = self.raytrace(reflected_ray, bounces - 1).hadamard(&info.material.albedo());
+        let emitted = info.material.emission(&info);
+        let mut reflected_ray = info.material.reflect(ray, &intersection, &info.normal);
+
+        // Interpolated normals can send reflected rays below the actual
+        // surface. Those are mirrored back above it
+        let below = reflected_ray.direction.dot(&info.geometric_normal);
+        if below < 0. && !info.material.double_sided() {
+            reflected_ray.direction -= 2. * below * info.geometric_normal;
+        }
+        let reflected = self.raytrace(&reflected_ray, bounces - 1).hadamard(&info.material.albedo(&info));
     
         emitted + reflected
     }
 
 }
+
+// The seed of a pixel's random generator in a render with the given seed
+fn pixel_seed(seed: u64, x: u32, y: u32) -> u64 {
+    seed ^ ((x as u64) << 32 | y as u64)
+}
@@ -1,114 +1,817 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::sync::Arc;
 use crate::geometry::ray::Ray;
 use crate::geometry::hitbox::HitBox;
 use crate::geometry::vector::Vector;
-use crate::scene::elements::{SceneElement, CollisionInfo};
-use crate::utils::utils::Axis;
+use crate::scene::elements::{SceneElement, CollisionInfo, ElementVisitor, Placement};
+use crate::utils::utils::{set_bits, Axis};
+use crate::config::{BvhSettings, EPSILON, PARALLEL_BUILD_SIZE, SAH_BINS};
+use crate::scene::sbvh::build_spatial;
+#[cfg(feature = "wide-bvh")]
+use crate::scene::wide_bvh::{collapse, traverse_wide, WideNode};
 
-pub fn build_bvh(mut elements: Vec<Arc<dyn SceneElement>>) -> Arc<dyn SceneElement> {
-    if elements.len() == 0 { return Arc::new(EmptyBVHNode::new()); }
+pub fn build_bvh(elements: Vec<Arc<dyn SceneElement>>) -> Arc<dyn SceneElement> {
+    build_bvh_with(elements, &BvhSettings::default())
+}
+
+pub fn build_bvh_with(mut elements: Vec<Arc<dyn SceneElement>>, settings: &BvhSettings) -> Arc<dyn SceneElement> {
     if elements.len() == 1 { return elements.remove(0); }
 
-    let n = elements.len();
-    let mut best_axis = Axis::X;
-    let mut best_index = 0;
-    let mut best_cost = f64::INFINITY;
+    let hitboxes: Vec<HitBox> = elements.iter().map(|element| *element.hitbox()).collect();
+    let tree = BvhTree::build(&hitboxes, settings);
+    let hitbox = tree.hitbox();
+
+    Arc::new(Bvh { tree, elements, hitbox })
+}
+
+// Partitions items at the split the surface area heuristic finds cheapest, and
+// returns how many of them belong to the left side, or None if keeping them in
+// a leaf is cheaper. Items whose centers can't be told apart are split in
+// half. There must be at least two items
+pub fn sah_split<T>(items: &mut [T], hitbox: impl Fn(&T) -> &HitBox, settings: &BvhSettings) -> Option<usize> {
+    let split = ObjectSplit::find(items, &hitbox);
+    let mut bounds = *hitbox(&items[0]);
+    for item in items.iter() {
+        bounds.merge(hitbox(item));
+    }
+
+    if keep_leaf(items.len(), &bounds, split.as_ref().map(|split| split.cost), settings) { return None }
+    match split {
+        Some(split) => Some(split.partition(items, &hitbox)),
+        None => Some(items.len() / 2)
+    }
+}
+
+// Whether n items with the given bounds are cheaper as a leaf than split with
+// the given cost, the sum of the area times the number of items of each side
+pub fn keep_leaf(n: usize, bounds: &HitBox, split_cost: Option<f64>, settings: &BvhSettings) -> bool {
+    if n > settings.max_leaf_size { return false }
+    let Some(split_cost) = split_cost else { return true };
 
-    for axis in [Axis::X, Axis::Y, Axis::Z] {
-        let mut lareas = vec![0.; n - 1];
-        let mut rareas = vec![0.; n - 1];
-        elements.sort_by(|a, b| a.hitbox().compare(b.hitbox(), axis));
+    // Costs are relative to a ray that hits bounds. Flat or empty bounds can't
+    // weigh the children, so they are split whenever there is a split
+    let area = bounds.area();
+    if area <= 0. { return false }
+    settings.intersection_cost * n as f64 <= settings.traversal_cost + settings.intersection_cost * split_cost / area
+}
 
-        let mut lhitbox: HitBox = *elements[0].hitbox();
-        lareas[0] = lhitbox.area();
-        for i in 1..n - 1 {
-            lhitbox.merge(elements[i].hitbox());
-            lareas[i] = lhitbox.area();
+// The cheapest way to split items in two according to the surface area
+// heuristic. Items are binned by the center of their hitbox, so only the splits
+// between SAH_BINS bins are tried on each axis
+pub struct ObjectSplit {
+    axis: Axis,
+    bin: usize,
+    low: f64,
+    high: f64,
+    // The area of each side times its number of items, added up
+    pub cost: f64,
+    pub left: HitBox,
+    pub right: HitBox
+}
+
+impl ObjectSplit {
+    // Returns None if the centers of the items can't be told apart
+    pub fn find<T>(items: &[T], hitbox: impl Fn(&T) -> &HitBox) -> Option<Self> {
+        let n = items.len();
+        let mut low = hitbox(&items[0]).center();
+        let mut high = low;
+        for item in items {
+            let center = hitbox(item).center();
+            low = Vector::new(low.x.min(center.x), low.y.min(center.y), low.z.min(center.z));
+            high = Vector::new(high.x.max(center.x), high.y.max(center.y), high.z.max(center.z));
         }
 
-        let mut rhitbox: HitBox = *elements[n - 1].hitbox();
-        rareas[n - 2] = rhitbox.area();
-        for i in (0..n - 1).rev() {
-            rhitbox.merge(elements[i].hitbox());
-            rareas[i] = rhitbox.area();
+        let mut best: Option<Self> = None;
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            let (low, high) = (component(&low, axis), component(&high, axis));
+            if high - low <= 0. { continue }
+
+            let mut bins: [Option<HitBox>; SAH_BINS] = [None; SAH_BINS];
+            let mut counts = [0; SAH_BINS];
+            for item in items {
+                let index = bin(hitbox(item), axis, low, high);
+                counts[index] += 1;
+                merge_bounds(&mut bins[index], &Some(*hitbox(item)));
+            }
+
+            // The right side of the split after each bin and its cost
+            let mut rights: [Option<HitBox>; SAH_BINS] = [None; SAH_BINS];
+            let mut right_costs = [0.; SAH_BINS];
+            let mut right: Option<HitBox> = None;
+            let mut count = 0;
+            for i in (1..SAH_BINS).rev() {
+                merge_bounds(&mut right, &bins[i]);
+                count += counts[i];
+                rights[i - 1] = right;
+                right_costs[i - 1] = right.map_or(0., |bounds| bounds.area() * count as f64);
+            }
+
+            let mut left: Option<HitBox> = None;
+            let mut count = 0;
+            for i in 0..SAH_BINS - 1 {
+                merge_bounds(&mut left, &bins[i]);
+                count += counts[i];
+                if count == 0 || count == n { continue }
+
+                let cost = left.map_or(0., |bounds| bounds.area() * count as f64) + right_costs[i];
+                if best.as_ref().is_none_or(|best| cost < best.cost)
+                    && let (Some(left), Some(right)) = (left, rights[i]) {
+                    best = Some(Self { axis, bin: i, low, high, cost, left, right });
+                }
+            }
         }
+        best
+    }
 
-        for i in 0..n - 1 {
-            let cost = lareas[i] * (i + 1) as f64 + rareas[i] * (n - i - 1) as f64;
-            if cost < best_cost {
-                best_axis = axis;
-                best_index = i;
-                best_cost = cost;
+    // Moves the items of the left side before the others, and returns how many
+    // there are
+    pub fn partition<T>(&self, items: &mut [T], hitbox: impl Fn(&T) -> &HitBox) -> usize {
+        let mut count = 0;
+        for i in 0..items.len() {
+            if bin(hitbox(&items[i]), self.axis, self.low, self.high) <= self.bin {
+                items.swap(i, count);
+                count += 1;
             }
         }
+        count
+    }
+}
+
+// The bin of a hitbox for splits along axis between centers low and high
+fn bin(hitbox: &HitBox, axis: Axis, low: f64, high: f64) -> usize {
+    let position = (component(&hitbox.center(), axis) - low) / (high - low);
+    ((position * SAH_BINS as f64) as usize).min(SAH_BINS - 1)
+}
+
+// Statistics of a BVH, gathered with SceneElement::bvh_stats or
+// Mesh::bvh_stats
+#[derive(Debug, Clone, Default)]
+pub struct BvhStats {
+    // Inner nodes and leaves
+    pub nodes: usize,
+    pub leaves: usize,
+    // Number of levels, 1 for a BVH that is a single leaf
+    pub depth: usize,
+    // How many leaves hold each number of elements
+    pub leaf_sizes: Vec<usize>,
+    root_area: f64,
+    inner_area: f64,
+    leaf_area: f64
+}
+
+impl BvhStats {
+    pub fn add_inner(&mut self, hitbox: &HitBox, depth: usize) {
+        self.add_node(hitbox, depth);
+        self.inner_area += hitbox.area();
+    }
+
+    pub fn add_leaf(&mut self, hitbox: &HitBox, count: usize, depth: usize) {
+        self.add_node(hitbox, depth);
+        self.leaves += 1;
+        if self.leaf_sizes.len() <= count {
+            self.leaf_sizes.resize(count + 1, 0);
+        }
+        self.leaf_sizes[count] += 1;
+        self.leaf_area += hitbox.area() * count as f64;
+    }
+
+    fn add_node(&mut self, hitbox: &HitBox, depth: usize) {
+        if depth == 0 { self.root_area = hitbox.area() }
+        self.nodes += 1;
+        self.depth = self.depth.max(depth + 1);
     }
-    
-    let mut hitbox = *elements[0].hitbox();
-    for element in &elements {
-        hitbox.merge(element.hitbox());
+
+    // Total number of elements in the leaves
+    pub fn elements(&self) -> usize {
+        self.leaf_sizes.iter().enumerate().map(|(size, count)| size * count).sum()
     }
 
-    elements.sort_by(|a, b| a.hitbox().compare(b.hitbox(), best_axis));
-    let left = build_bvh(elements.drain(0..=best_index).collect());
-    let right = build_bvh(elements);
+    pub fn mean_leaf_size(&self) -> f64 {
+        self.elements() as f64 / self.leaves.max(1) as f64
+    }
 
-    Arc::new(BVHNode::new(left, right, hitbox))
+    // The expected cost of tracing a ray that hits the root through the BVH.
+    // Each node costs what testing it does, weighted by the probability that
+    // the ray hits it, which is the ratio of their surface areas
+    pub fn sah_cost(&self, settings: &BvhSettings) -> f64 {
+        if self.root_area <= 0. {
+            return settings.traversal_cost * (self.nodes - self.leaves) as f64 + settings.intersection_cost * self.elements() as f64;
+        }
+        (settings.traversal_cost * self.inner_area + settings.intersection_cost * self.leaf_area) / self.root_area
+    }
 }
 
-struct BVHNode {
-    left: Arc<dyn SceneElement>,
-    right: Arc<dyn SceneElement>,
-    hitbox: HitBox
+pub fn merge_bounds(bounds: &mut Option<HitBox>, other: &Option<HitBox>) {
+    match (bounds.as_mut(), other) {
+        (Some(bounds), Some(other)) => bounds.merge(other),
+        (None, Some(other)) => *bounds = Some(*other),
+        _ => ()
+    }
 }
 
-impl BVHNode {
-    pub fn new(left: Arc<dyn SceneElement>, right: Arc<dyn SceneElement>, hitbox: HitBox) -> Self {
-        Self {left, right, hitbox}
+pub fn component(vector: &Vector, axis: Axis) -> f64 {
+    match axis {
+        Axis::X => vector.x,
+        Axis::Y => vector.y,
+        Axis::Z => vector.z
     }
 }
 
-impl SceneElement for BVHNode {
-    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
-        if !self.hitbox.intersects(ray, max_distance) { return None }
+// A node of a flattened BVH. Bounds are stored as f32, rounded outwards, so
+// that a node takes 32 bytes. Leaves hold count items starting at first.
+// Inner nodes have their left child right after them and their right child at
+// first, and count holds INNER_NODE, the axis along which their children are
+// furthest apart and whether the right child comes first along it
+#[derive(Clone, Copy)]
+pub struct BvhNode {
+    start: [f32; 3],
+    end: [f32; 3],
+    first: u32,
+    count: u32
+}
 
-        if self.left.hitbox().distance(&ray.origin) < self.right.hitbox().distance(&ray.origin) {
-            let l = self.left.collide(ray, max_distance);
-            if l.is_none() { return self.right.collide(ray, max_distance) }
-            let left = l.unwrap();
+const _: () = assert!(std::mem::size_of::<BvhNode>() == 32);
 
-            let r = self.right.collide(ray, left.distance);
-            if r.is_none() { return Some(left) }
-            r
-        } else {
-            let r = self.right.collide(ray, max_distance);
-            if r.is_none() { return self.left.collide(ray, max_distance) }
-            let right = r.unwrap();
+const INNER_NODE: u32 = 1 << 31;
+const RIGHT_FIRST: u32 = 1 << 2;
+const AXIS_MASK: u32 = 3;
+
+impl BvhNode {
+    pub fn leaf(hitbox: &HitBox, first: usize, count: usize) -> Self {
+        let (start, end) = bounds_f32(hitbox);
+        Self { start, end, first: first as u32, count: count as u32 }
+    }
+
+    pub fn inner(hitbox: &HitBox, right: usize, left_box: &HitBox, right_box: &HitBox) -> Self {
+        let (start, end) = bounds_f32(hitbox);
+        let offset = right_box.center() - left_box.center();
+        let offset = [offset.x, offset.y, offset.z];
+        let axis = (0..3).max_by(|&a, &b| offset[a].abs().total_cmp(&offset[b].abs())).unwrap();
+        let right_first = if offset[axis] < 0. { RIGHT_FIRST } else { 0 };
+        Self { start, end, first: right as u32, count: INNER_NODE | right_first | axis as u32 }
+    }
 
-            let l = self.left.collide(ray, right.distance);
-            if l.is_none() { return Some(right) }
-            l
+    pub fn hitbox(&self) -> HitBox {
+        HitBox::new(
+            Vector::new(self.start[0] as f64, self.start[1] as f64, self.start[2] as f64),
+            Vector::new(self.end[0] as f64, self.end[1] as f64, self.end[2] as f64)
+        )
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count & INNER_NODE == 0
+    }
+
+    // The range of items of a leaf
+    pub fn items(&self) -> std::ops::Range<usize> {
+        self.first as usize..(self.first + self.count) as usize
+    }
+
+    // Moves the node to a larger list: inner nodes by nodes and leaves by
+    // items
+    pub fn offset(self, nodes: usize, items: usize) -> Self {
+        let first = self.first + if self.is_leaf() { items } else { nodes } as u32;
+        Self { first, ..self }
+    }
+
+    // The indexes of the children of an inner node, given its own
+    pub fn children(&self, index: usize) -> (usize, usize) {
+        (index + 1, self.first as usize)
+    }
+
+    // Same test as HitBox::intersects
+    fn intersects(&self, ray: &NodeRay, max_distance: f64) -> bool {
+        let mut tmin = f64::NEG_INFINITY;
+        let mut tmax = max_distance;
+        for axis in 0..3 {
+            let (start, end) = (self.start[axis] as f64, self.end[axis] as f64);
+            if ray.parallel[axis] {
+                if ray.origin[axis] < start || ray.origin[axis] > end { return false }
+                continue;
+            }
+
+            let t1 = (start - ray.origin[axis]) * ray.inverse[axis];
+            let t2 = (end - ray.origin[axis]) * ray.inverse[axis];
+            tmin = tmin.max(t1.min(t2));
+            tmax = tmax.min(t1.max(t2));
         }
+        tmin <= tmax && tmax >= 0.
     }
+}
 
-    fn hitbox(&self) -> &HitBox {
-        &self.hitbox
+// Rounds a hitbox outwards to f32
+fn bounds_f32(hitbox: &HitBox) -> ([f32; 3], [f32; 3]) {
+    let (start, end) = (hitbox.start(), hitbox.end());
+    let down = |value: f64| {
+        let rounded = value as f32;
+        if rounded as f64 > value { rounded.next_down() } else { rounded }
+    };
+    let up = |value: f64| {
+        let rounded = value as f32;
+        if (rounded as f64) < value { rounded.next_up() } else { rounded }
+    };
+    ([down(start.x), down(start.y), down(start.z)], [up(end.x), up(end.y), up(end.z)])
+}
+
+// A ray prepared to be tested against many nodes
+struct NodeRay {
+    origin: [f64; 3],
+    inverse: [f64; 3],
+    negative: [bool; 3],
+    // Rays almost parallel to an axis only check that they are between the
+    // node's planes, like HitBox::intersects
+    parallel: [bool; 3]
+}
+
+impl NodeRay {
+    fn new(ray: &Ray) -> Self {
+        let direction = [ray.direction.x, ray.direction.y, ray.direction.z];
+        Self {
+            origin: [ray.origin.x, ray.origin.y, ray.origin.z],
+            inverse: direction.map(|d| 1. / d),
+            negative: direction.map(|d| d < 0.),
+            parallel: direction.map(|d| d.abs() < EPSILON)
+        }
     }
 }
 
-struct EmptyBVHNode {
-    hitbox: HitBox
+// Bounds of the origins and inverse directions of a packet of rays, which
+// tell when a node is missed by all of them with a single test
+// https://www.sci.utah.edu/~wald/Publications/2007/BVH/download/togbvh.pdf
+struct PacketBounds {
+    origin: [(f64, f64); 3],
+    inverse: [(f64, f64); 3],
+    // Axes along which the rays go in different directions, or are parallel
+    // to, can't be bounded and are left out
+    bounded: [bool; 3]
 }
 
-impl EmptyBVHNode {
-    pub fn new() -> Self {
-        let hitbox = HitBox::new(Vector::ZERO, Vector::ZERO);
-        Self {hitbox}
+impl PacketBounds {
+    fn new(rays: &[NodeRay], mask: u64) -> Self {
+        let mut bounds = Self {
+            origin: [(f64::INFINITY, f64::NEG_INFINITY); 3],
+            inverse: [(f64::INFINITY, f64::NEG_INFINITY); 3],
+            bounded: [true; 3]
+        };
+        let first = &rays[mask.trailing_zeros() as usize];
+        for ray in set_bits(mask).map(|i| &rays[i]) {
+            for axis in 0..3 {
+                let (origin, inverse) = (&mut bounds.origin[axis], &mut bounds.inverse[axis]);
+                *origin = (origin.0.min(ray.origin[axis]), origin.1.max(ray.origin[axis]));
+                *inverse = (inverse.0.min(ray.inverse[axis]), inverse.1.max(ray.inverse[axis]));
+                bounds.bounded[axis] &= !ray.parallel[axis] && ray.negative[axis] == first.negative[axis];
+            }
+        }
+        bounds
+    }
+
+    // Whether every ray misses the node. It can say they don't when they do
+    fn misses(&self, node: &BvhNode) -> bool {
+        let mut entry = f64::NEG_INFINITY;
+        let mut exit = f64::INFINITY;
+        for axis in (0..3).filter(|&axis| self.bounded[axis]) {
+            let (start, end) = (node.start[axis] as f64, node.end[axis] as f64);
+            let (near, far) = if self.inverse[axis].0 < 0. { (end, start) } else { (start, end) };
+            let (low, high) = self.origin[axis];
+            entry = entry.max(product_bounds((near - high, near - low), self.inverse[axis]).0);
+            exit = exit.min(product_bounds((far - high, far - low), self.inverse[axis]).1);
+        }
+        entry > exit || exit < 0.
     }
 }
 
-impl SceneElement for EmptyBVHNode {
-    fn collide(&self, _: &Ray, _: f64) -> Option<CollisionInfo> { None }
+// The smallest and largest products of a value in a and one in b
+fn product_bounds(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    let products = [a.0 * b.0, a.0 * b.1, a.1 * b.0, a.1 * b.1];
+    (products.iter().copied().fold(f64::INFINITY, f64::min), products.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+}
+
+// A flattened BVH over a list of items. Leaves refer to the items through a
+// list of indices, where items split by spatial splits appear more than once.
+// With the wide-bvh feature the BVH is also collapsed into a 4-wide one, which
+// is the one rays traverse
+#[derive(Clone, Default)]
+pub struct BvhTree {
+    nodes: Vec<BvhNode>,
+    indices: Vec<u32>,
+    #[cfg(feature = "wide-bvh")]
+    wide_nodes: Vec<WideNode>
+}
+
+impl BvhTree {
+    // Builds a BVH over items given their hitboxes. Spatial splits clip the
+    // hitboxes themselves
+    pub fn build(hitboxes: &[HitBox], settings: &BvhSettings) -> Self {
+        Self::build_clipped(hitboxes, settings, |i, bounds| hitboxes[i].intersection(bounds))
+    }
+
+    // Like build, but spatial splits call clip to find the bounds of the part
+    // of an item inside a box, if there is any
+    pub fn build_clipped(hitboxes: &[HitBox], settings: &BvhSettings, clip: impl Fn(usize, &HitBox) -> Option<HitBox> + Sync) -> Self {
+        if hitboxes.is_empty() { return Self::default() }
+        if settings.spatial_splits {
+            let (nodes, indices) = build_spatial(hitboxes, settings, &clip);
+            return Self::from_parts(nodes, indices);
+        }
+
+        let mut items: Vec<(u32, HitBox)> = hitboxes.iter().enumerate().map(|(i, hitbox)| (i as u32, *hitbox)).collect();
+        let nodes = build_subtree(&mut items, 0, settings);
+        Self::from_parts(nodes, items.into_iter().map(|(i, _)| i).collect())
+    }
+
+    pub fn from_parts(nodes: Vec<BvhNode>, indices: Vec<u32>) -> Self {
+        Self {
+            #[cfg(feature = "wide-bvh")]
+            wide_nodes: collapse(&nodes),
+            nodes,
+            indices
+        }
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn hitbox(&self) -> HitBox {
+        self.nodes.first().map_or(HitBox::new(Vector::ZERO, Vector::ZERO), BvhNode::hitbox)
+    }
+
+    // Walks the nodes that the ray hits before max_distance, nearest first,
+    // and calls hit with each item in the leaves it reaches and the distance
+    // of the closest hit so far. hit returns the distance of the item when it
+    // is hit closer than that. Returns the distance of the closest hit
+    pub fn traverse(&self, ray: &Ray, max_distance: f64, mut hit: impl FnMut(usize, f64) -> Option<f64>) -> f64 {
+        self.walk(ray, max_distance, |i, closest| hit(i, closest).map_or(ItemHit::Miss, ItemHit::Closer))
+    }
+
+    // Like traverse, but stops at the first item hit returns true for, in no
+    // particular order. Returns whether there was one
+    pub fn any_hit(&self, ray: &Ray, max_distance: f64, mut hit: impl FnMut(usize, f64) -> bool) -> bool {
+        let mut found = false;
+        self.walk(ray, max_distance, |i, closest| {
+            found = hit(i, closest);
+            if found { ItemHit::Stop } else { ItemHit::Miss }
+        });
+        found
+    }
+
+    // Walks the nodes that any ray of a packet hits before its closest hit so
+    // far, nearest first for the first ray. Only the rays whose index is set in
+    // mask are traced, so packets hold at most 64 rays. hit is called with each
+    // item in the leaves it reaches, the rays that reach it as a mask, and the
+    // distances of their closest hits, which it updates. The binary BVH is
+    // traversed even with the wide-bvh feature
+    pub fn traverse_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], mut hit: impl FnMut(usize, u64, &mut [f64])) {
+        if self.nodes.is_empty() || mask == 0 { return }
+
+        let node_rays: Vec<NodeRay> = rays.iter().map(NodeRay::new).collect();
+        let first = &node_rays[mask.trailing_zeros() as usize];
+        let bounds = PacketBounds::new(&node_rays, mask);
+        let mut stack: Vec<(usize, u64)> = Vec::with_capacity(64);
+        stack.push((0, mask));
+
+        while let Some((index, mask)) = stack.pop() {
+            let node = &self.nodes[index];
+            if bounds.misses(node) { continue }
+            let active = set_bits(mask)
+                .filter(|&i| node.intersects(&node_rays[i], closest[i]))
+                .fold(0, |active, i| active | 1 << i);
+            if active == 0 { continue }
+
+            if node.is_leaf() {
+                for i in node.items() {
+                    hit(self.indices[i] as usize, active, closest);
+                }
+                continue;
+            }
+
+            let (left, right) = (index + 1, node.first as usize);
+            let axis = (node.count & AXIS_MASK) as usize;
+            let right_first = node.count & RIGHT_FIRST != 0;
+            if first.negative[axis] == right_first {
+                stack.push((right, active));
+                stack.push((left, active));
+            } else {
+                stack.push((left, active));
+                stack.push((right, active));
+            }
+        }
+    }
+
+    // Like traverse, where hit can also stop the traversal
+    fn walk(&self, ray: &Ray, max_distance: f64, mut hit: impl FnMut(usize, f64) -> ItemHit) -> f64 {
+        let indices = &self.indices;
+        let hit = |i: usize, closest: f64| hit(indices[i] as usize, closest);
+        #[cfg(feature = "wide-bvh")]
+        return traverse_wide(&self.wide_nodes, ray, max_distance, hit);
+        #[cfg(not(feature = "wide-bvh"))]
+        return traverse(&self.nodes, ray, max_distance, hit);
+    }
+
+    // Renumbers the items in the order the leaves first refer to them, so
+    // that they can be stored in that order, and returns which item was at
+    // each new position
+    pub fn sort_items(&mut self) -> Vec<u32> {
+        let mut order = Vec::new();
+        let mut positions: HashMap<u32, u32> = HashMap::new();
+        for index in &mut self.indices {
+            *index = *positions.entry(*index).or_insert_with(|| {
+                order.push(*index);
+                order.len() as u32 - 1
+            });
+        }
+        order
+    }
+
+    // Updates the bounds of the nodes to new hitboxes of the items, keeping
+    // the structure of the tree. The more the items moved, the slower the tree
+    // gets to traverse, which sah_cost measures. Items clipped by spatial
+    // splits get their whole hitbox back
+    pub fn refit(&mut self, hitboxes: &[HitBox]) {
+        // Children always come after their parent, so going backwards updates
+        // them first
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            self.nodes[index] = if node.is_leaf() {
+                let items = &self.indices[node.items()];
+                let mut hitbox = hitboxes[items[0] as usize];
+                for &item in items {
+                    hitbox.merge(&hitboxes[item as usize]);
+                }
+                BvhNode::leaf(&hitbox, node.first as usize, node.count as usize)
+            } else {
+                let (left, right) = node.children(index);
+                let (left_box, right_box) = (self.nodes[left].hitbox(), self.nodes[right].hitbox());
+                let mut hitbox = left_box;
+                hitbox.merge(&right_box);
+                BvhNode::inner(&hitbox, right, &left_box, &right_box)
+            };
+        }
+
+        #[cfg(feature = "wide-bvh")]
+        {
+            self.wide_nodes = collapse(&self.nodes);
+        }
+    }
+
+    // The SAH cost of the tree, see BvhStats::sah_cost
+    pub fn sah_cost(&self, settings: &BvhSettings) -> f64 {
+        let mut stats = BvhStats::default();
+        self.stats(&mut stats, 0);
+        stats.sah_cost(settings)
+    }
+
+    // Writes the BVH in the format read by read
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+        out.write_all(&(self.indices.len() as u64).to_le_bytes())?;
+        for node in &self.nodes {
+            for value in node.start.iter().chain(&node.end) {
+                out.write_all(&value.to_le_bytes())?;
+            }
+            out.write_all(&node.first.to_le_bytes())?;
+            out.write_all(&node.count.to_le_bytes())?;
+        }
+        for index in &self.indices {
+            out.write_all(&index.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Reads a BVH written by write over the given number of items. Malformed
+    // data is an InvalidData error
+    pub fn read(input: &mut impl Read, items: usize) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut word = [0; 4];
+        let mut read_word = |input: &mut dyn Read| -> io::Result<[u8; 4]> {
+            input.read_exact(&mut word)?;
+            Ok(word)
+        };
+
+        let mut counts = [0; 2];
+        for count in &mut counts {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            *count = u64::from_le_bytes(bytes) as usize;
+        }
+        let [node_count, index_count] = counts;
+
+        // Counts aren't trusted to preallocate, reading past the end fails
+        // first if they are wrong
+        let mut nodes = Vec::new();
+        for index in 0..node_count {
+            let mut values = [0.; 6];
+            for value in &mut values {
+                *value = f32::from_le_bytes(read_word(input)?);
+            }
+            let node = BvhNode {
+                start: [values[0], values[1], values[2]],
+                end: [values[3], values[4], values[5]],
+                first: u32::from_le_bytes(read_word(input)?),
+                count: u32::from_le_bytes(read_word(input)?)
+            };
+
+            // Children always come after their parent, so traversal can't loop
+            let valid = if node.is_leaf() {
+                node.count > 0 && node.first as usize + node.count as usize <= index_count
+            } else {
+                node.count & !(INNER_NODE | RIGHT_FIRST | AXIS_MASK) == 0 && node.count & AXIS_MASK < 3
+                    && index + 1 < node.first as usize && (node.first as usize) < node_count
+            };
+            if !valid { return Err(invalid("malformed BVH node")) }
+            nodes.push(node);
+        }
+
+        let mut indices = Vec::new();
+        for _ in 0..index_count {
+            let index = u32::from_le_bytes(read_word(input)?);
+            if index as usize >= items { return Err(invalid("BVH item out of range")) }
+            indices.push(index);
+        }
+        Ok(Self::from_parts(nodes, indices))
+    }
+
+    // Adds the nodes of the binary BVH to stats, with the root at the given
+    // depth
+    pub fn stats(&self, stats: &mut BvhStats, depth: usize) {
+        if self.nodes.is_empty() { return }
+
+        let mut stack = vec![(0, depth)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.is_leaf() {
+                stats.add_leaf(&node.hitbox(), node.count as usize, depth);
+            } else {
+                stats.add_inner(&node.hitbox(), depth);
+                stack.push((node.first as usize, depth + 1));
+                stack.push((index + 1, depth + 1));
+            }
+        }
+    }
+}
+
+// Builds the nodes of a subtree in their own list, with the children of inner
+// nodes relative to the start of it. Big subtrees build both of their children
+// in parallel and then append them
+fn build_subtree<T: Send>(items: &mut [(T, HitBox)], offset: usize, settings: &BvhSettings) -> Vec<BvhNode> {
+    let mut nodes = Vec::new();
+    if items.len() < PARALLEL_BUILD_SIZE {
+        add_nodes(&mut nodes, items, offset, settings);
+        return nodes;
+    }
+
+    let hitbox = bounds(items);
+    let Some(split) = sah_split(items, |item| &item.1, settings) else {
+        add_nodes(&mut nodes, items, offset, settings);
+        return nodes;
+    };
+
+    let (left, right) = items.split_at_mut(split);
+    let (left, right) = rayon::join(|| build_subtree(left, offset, settings), || build_subtree(right, offset + split, settings));
+
+    nodes.reserve_exact(1 + left.len() + right.len());
+    nodes.push(BvhNode::inner(&hitbox, 1 + left.len(), &left[0].hitbox(), &right[0].hitbox()));
+    let right_start = nodes[0].first as usize;
+    nodes.extend(left.into_iter().map(|node| node.offset(1, 0)));
+    nodes.extend(right.into_iter().map(|node| node.offset(right_start, 0)));
+    nodes
+}
+
+// Adds a subtree to nodes and returns the index of its root
+fn add_nodes<T>(nodes: &mut Vec<BvhNode>, items: &mut [(T, HitBox)], offset: usize, settings: &BvhSettings) -> usize {
+    let hitbox = bounds(items);
+    let index = nodes.len();
+    nodes.push(BvhNode::leaf(&hitbox, offset, items.len()));
+    if items.len() == 1 { return index }
+    let Some(split) = sah_split(items, |item| &item.1, settings) else { return index };
+
+    let (left, right) = items.split_at_mut(split);
+    let left = add_nodes(nodes, left, offset, settings);
+    let right = add_nodes(nodes, right, offset + split, settings);
+    nodes[index] = BvhNode::inner(&hitbox, right, &nodes[left].hitbox(), &nodes[right].hitbox());
+    index
+}
+
+pub fn bounds<T>(items: &[(T, HitBox)]) -> HitBox {
+    let mut hitbox = items[0].1;
+    for (_, item) in items {
+        hitbox.merge(item);
+    }
+    hitbox
+}
+
+// Traverses a binary BVH, see BvhTree::traverse. Children are visited in the
+// order the ray reaches them along the axis of their node. With the wide-bvh
+// feature it is only kept to compare traversals
+pub fn traverse(nodes: &[BvhNode], ray: &Ray, max_distance: f64, mut hit: impl FnMut(usize, f64) -> ItemHit) -> f64 {
+    let mut closest = max_distance;
+    if nodes.is_empty() { return closest }
+
+    let node_ray = NodeRay::new(ray);
+    let mut stack: Vec<usize> = Vec::with_capacity(64);
+    stack.push(0);
+
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index];
+        if !node.intersects(&node_ray, closest) { continue }
+
+        if node.is_leaf() {
+            for i in node.items() {
+                match hit(i, closest) {
+                    ItemHit::Miss => (),
+                    ItemHit::Closer(distance) => closest = distance,
+                    ItemHit::Stop => return closest
+                }
+            }
+            continue;
+        }
+
+        // Visit the child the ray reaches first along the node's axis first,
+        // so that the other one can be skipped more often
+        let (left, right) = (index + 1, node.first as usize);
+        let axis = (node.count & AXIS_MASK) as usize;
+        let right_first = node.count & RIGHT_FIRST != 0;
+        if node_ray.negative[axis] == right_first {
+            stack.push(right);
+            stack.push(left);
+        } else {
+            stack.push(left);
+            stack.push(right);
+        }
+    }
+    closest
+}
+
+// What an item of a leaf did with the ray during a traversal
+pub enum ItemHit {
+    Miss,
+    // The item was hit at this distance, closer than any hit so far
+    Closer(f64),
+    // The traversal can stop
+    Stop
+}
+
+// A BVH over scene elements, flattened into a list of nodes. Leaves refer to
+// the elements through a list of indices
+struct Bvh {
+    tree: BvhTree,
+    elements: Vec<Arc<dyn SceneElement>>,
+    hitbox: HitBox
+}
+
+impl SceneElement for Bvh {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        let mut closest: Option<CollisionInfo> = None;
+        self.tree.traverse(ray, max_distance, |i, distance| {
+            let info = self.elements[i].collide(ray, distance)?;
+            let distance = info.distance;
+            closest = Some(info);
+            Some(distance)
+        });
+        closest
+    }
+
     fn hitbox(&self) -> &HitBox {
         &self.hitbox
     }
+
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.tree.any_hit(ray, max_distance, |i, _| self.elements[i].any_hit(ray, max_distance))
+    }
+
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        self.tree.traverse_packet(rays, mask, closest, |i, active, closest| {
+            self.elements[i].collide_packet(rays, active, closest, hits);
+        });
+    }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        let mut elements: Vec<usize> = Vec::new();
+        self.tree.traverse(ray, max_distance, |i, _| {
+            elements.push(i);
+            None
+        });
+
+        // Elements split by spatial splits are in several leaves
+        elements.sort_unstable();
+        elements.dedup();
+        for i in elements {
+            self.elements[i].collect_hits(ray, max_distance, hits);
+        }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        for element in &self.elements {
+            element.visit(visitor, placement);
+        }
+    }
+
+    fn bvh_stats(&self, stats: &mut BvhStats, depth: usize) {
+        self.tree.stats(stats, depth);
+    }
 }
@@ -0,0 +1,138 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use once_cell::sync::Lazy;
+use crate::config::{BvhSettings, MIN_OVERLAP, SAH_BINS, SPATIAL_BINS};
+use crate::geometry::transform::Transform;
+use crate::scene::materials::Material;
+use crate::scene::mesh::Mesh;
+
+// Every cache file starts with MAGIC, the version and the key it was written
+// for. Bump CACHE_VERSION whenever Mesh::write_binary or the way meshes are
+// built changes
+const MAGIC: &[u8; 8] = b"CRCLMESH";
+const CACHE_VERSION: u32 = 5;
+
+// Where built meshes are cached. The cache is off until a directory is set
+static CACHE_DIRECTORY: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn set_cache_directory(directory: Option<PathBuf>) {
+    *CACHE_DIRECTORY.lock().unwrap() = directory;
+}
+
+pub fn cache_directory() -> Option<PathBuf> {
+    CACHE_DIRECTORY.lock().unwrap().clone()
+}
+
+// Loads the mesh read from path by build from the cache, or builds it and
+// caches it. The key hashes the contents of the file, the transform, the
+// settings meshes are built with and kind, which should name the reader and
+// any setting that changes its output.
+// Materials aren't cached, so the mesh gets the given ones. A cache that can't
+// be read or written is never an error, the mesh is just built again
+pub fn cached_mesh<E>(
+    kind: &str,
+    path: &Path,
+    transform: &Transform,
+    materials: Vec<Arc<dyn Material>>,
+    build: impl FnOnce() -> Result<Mesh, E>
+) -> Result<Mesh, E> {
+    let Some(directory) = cache_directory() else { return build() };
+    let Ok(bytes) = fs::read(path) else { return build() };
+
+    let key = cache_key(kind, &bytes, transform);
+    let cache_path = directory.join(format!("{:016x}.mesh", key));
+    if let Ok(mesh) = read_cache(&cache_path, key, materials) {
+        return Ok(mesh);
+    }
+
+    let mesh = build()?;
+    if let Err(e) = write_cache(&directory, &cache_path, key, &mesh) {
+        eprintln!("Warning: couldn't cache {}: {}", path.display(), e);
+    }
+    Ok(mesh)
+}
+
+fn cache_key(kind: &str, bytes: &[u8], transform: &Transform) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(&CACHE_VERSION.to_le_bytes());
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.write(kind.as_bytes());
+    hasher.write(&(bytes.len() as u64).to_le_bytes());
+    hasher.write(bytes);
+    for value in transform.matrix().as_flattened() {
+        hasher.write(&value.to_bits().to_le_bytes());
+    }
+
+    // Meshes are built with the default settings, so changing them in
+    // config.rs must build them again
+    let settings = BvhSettings::default();
+    for value in [settings.traversal_cost, settings.intersection_cost, settings.max_split_growth, settings.rebuild_threshold, MIN_OVERLAP] {
+        hasher.write(&value.to_bits().to_le_bytes());
+    }
+    for value in [settings.max_leaf_size, SAH_BINS, SPATIAL_BINS] {
+        hasher.write(&(value as u64).to_le_bytes());
+    }
+    hasher.write(&[settings.spatial_splits as u8]);
+    hasher.finish()
+}
+
+fn read_cache(path: &Path, key: u64, materials: Vec<Arc<dyn Material>>) -> io::Result<Mesh> {
+    let mut input = BufReader::new(File::open(path)?);
+
+    let mut header = [0; 20];
+    input.read_exact(&mut header)?;
+    if header[..8] != MAGIC[..] || header[8..12] != CACHE_VERSION.to_le_bytes() || header[12..] != key.to_le_bytes() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stale cache file"));
+    }
+
+    let mesh = Mesh::read_binary(&mut input, materials)?;
+    // Trailing data means the file isn't what was written
+    if input.read(&mut [0])? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "trailing data"));
+    }
+    Ok(mesh)
+}
+
+// Writes to a temporary file first and renames it, so other processes never
+// read a file that is half written
+fn write_cache(directory: &Path, path: &Path, key: u64, mesh: &Mesh) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let temporary = path.with_extension(format!("tmp{}", std::process::id()));
+
+    let result = (|| {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&CACHE_VERSION.to_le_bytes())?;
+        out.write_all(&key.to_le_bytes())?;
+        mesh.write_binary(&mut out)?;
+        out.into_inner()?.sync_all()?;
+        fs::rename(&temporary, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+// 64 bit FNV-1a, which is stable across builds unlike std's DefaultHasher
+// http://www.isthe.com/chongo/tech/comp/fnv/
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -2,28 +2,129 @@ use std::sync::Arc;
 use crate::geometry::hitbox::HitBox;
 use crate::geometry::vector::Vector;
 use crate::geometry::ray::Ray;
+use crate::geometry::transform::Transform;
+use crate::scene::bvh::BvhStats;
 use crate::scene::materials::Material;
+use crate::scene::mesh::Mesh;
 use crate::config::*;
+use crate::utils::utils::set_bits;
 
 pub trait SceneElement: Send + Sync {
     fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo>;
     fn hitbox(&self) -> &HitBox;
+
+    // Whether the ray hits the element before max_distance. Elements made of
+    // many others stop at the first hit they find instead of the closest
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.collide(ray, max_distance).is_some()
+    }
+
+    // Finds the closest hits of a packet of rays, for the rays whose index is
+    // set in mask. closest holds the distance of the closest hit of each ray
+    // so far and is updated, and hits is only set for the rays that hit the
+    // element closer than that. Elements made of many others trace the whole
+    // packet through their BVH at once
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        for i in set_bits(mask) {
+            if let Some(info) = self.collide(&rays[i], closest[i]) {
+                closest[i] = info.distance;
+                hits[i] = Some(info);
+            }
+        }
+    }
+
+    // Adds every hit of the ray before max_distance to hits, in no particular
+    // order. Elements that can be hit more than once by a ray must implement it
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        hits.extend(self.collide(ray, max_distance));
+    }
+
+    // Passes the geometry of the element to visitor. Elements without any
+    // don't need to implement it
+    fn visit(&self, _visitor: &mut dyn ElementVisitor, _placement: &Placement) {}
+
+    // Adds the nodes of the element to stats. Elements that aren't BVHs count
+    // as a leaf with a single element
+    fn bvh_stats(&self, stats: &mut BvhStats, depth: usize) {
+        stats.add_leaf(self.hitbox(), 1, depth);
+    }
+}
+
+// Receives the geometry of elements, for example to export it
+pub trait ElementVisitor {
+    fn sphere(&mut self, center: Vector, radius: f64, material: &Arc<dyn Material>, placement: &Placement);
+    fn triangle(&mut self, vertices: [Vector; 3], normals: Option<[Vector; 3]>, uvs: Option<[(f64, f64); 3]>, material: &Arc<dyn Material>, placement: &Placement);
+    fn mesh(&mut self, mesh: &Mesh, placement: &Placement);
+}
+
+// Where visited geometry is placed in the scene, and the material of the
+// instance it belongs to, which replaces its own
+#[derive(Clone)]
+pub struct Placement {
+    pub transform: Transform,
+    pub material: Option<Arc<dyn Material>>
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Self { transform: Transform::IDENTITY, material: None }
+    }
 }
 
 pub struct CollisionInfo {
     pub distance: f64,
+    // Point where the ray hit the element
+    pub position: Vector,
+    // Normal used for shading, which may be interpolated
     pub normal: Vector,
+    // Normal of the actual surface, used to move rays off it
+    pub geometric_normal: Vector,
+    // Whether the ray hit the side of the surface the normal points to
+    pub front_face: bool,
+    // Texture coordinates of the hit point
+    pub uv: (f64, f64),
+    // Barycentric coordinates of the hit point, for triangles
+    pub barycentric: Vector,
+    // Color of the surface at the hit point, from the vertex colors of meshes.
+    // White for everything else
+    pub color: Vector,
     pub material: Arc<dyn Material>
 }
 
 impl CollisionInfo {
-    pub fn new(distance: f64, normal: Vector, material: Arc<dyn Material>) -> Self {
+    pub fn new(ray: &Ray, distance: f64, normal: Vector, material: Arc<dyn Material>) -> Self {
         Self {
             distance,
+            position: ray.at(distance),
             normal,
+            geometric_normal: normal,
+            front_face: ray.direction.dot(&normal) < 0.,
+            uv: (0., 0.),
+            barycentric: Vector::ZERO,
+            color: Vector::uniform(1.),
             material
         }
     }
+
+    pub fn with_shading_normal(mut self, normal: Vector) -> Self {
+        self.normal = normal;
+        self
+    }
+
+    pub fn with_uv(mut self, uv: (f64, f64)) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    pub fn with_barycentric(mut self, barycentric: Vector) -> Self {
+        self.barycentric = barycentric;
+        self
+    }
+
+    pub fn with_color(mut self, color: Vector) -> Self {
+        self.color = color;
+        self
+    }
 }
 
 pub struct Sphere {
@@ -42,10 +143,18 @@ impl Sphere {
         Self {center, radius, material, hitbox}
     }
 
-    // Returns the intersection distance of a ray and a sphere, if any
+    // Returns the intersection distance of a ray and a sphere before
+    // max_distance, if any
     fn distance(&self, ray: &Ray, max_distance: f64) -> Option<f64> {
         if !self.hitbox.intersects(ray, max_distance) { return None }
 
+        let (t1, t2) = self.roots(ray)?;
+        [t1, t2].into_iter().find(|&t| t > 0. && t <= max_distance)
+    }
+
+    // Returns the distances at which the line of the ray enters and leaves the
+    // sphere, if it hits it at all
+    fn roots(&self, ray: &Ray) -> Option<(f64, f64)> {
         let oc = ray.origin - self.center;
         let a = ray.direction.dot(&ray.direction);
         let b = 2. * oc.dot(&ray.direction);
@@ -57,22 +166,11 @@ impl Sphere {
         }
 
         let t1 = (-b - discriminant.sqrt()) / (2. * a);
-        if t1 > 0. {
-            return Some(t1);
-        }
-
         let t2 = (-b + discriminant.sqrt()) / (2. * a);
-        if t2 > 0. {
-            return Some(t2);
-        }
-
-        None
+        Some((t1, t2))
     }
-}
 
-impl SceneElement for Sphere {
-    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
-        let d = self.distance(ray, max_distance)?;
+    fn collision_info(&self, ray: &Ray, d: f64) -> CollisionInfo {
         let point = ray.at(d);
 
         // Normalizing a vector is expensive, since it requires calculating a
@@ -80,12 +178,39 @@ impl SceneElement for Sphere {
         // radius
         let normal = (point - self.center) / self.radius;
 
-        Some(CollisionInfo::new(d, normal, self.material.clone()))
+        // Spherical coordinates, with u going around the Y axis
+        // https://raytracing.github.io/books/RayTracingTheNextWeek.html#texturemapping/texturecoordinatesforspheres
+        let theta = (-normal.y).clamp(-1., 1.).acos();
+        let phi = (-normal.z).atan2(normal.x) + std::f64::consts::PI;
+        let uv = (phi / (2. * std::f64::consts::PI), theta / std::f64::consts::PI);
+
+        CollisionInfo::new(ray, d, normal, self.material.clone()).with_uv(uv)
+    }
+}
+
+impl SceneElement for Sphere {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        let d = self.distance(ray, max_distance)?;
+        Some(self.collision_info(ray, d))
     }
 
     fn hitbox(&self) -> &HitBox {
         &self.hitbox
     }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        if !self.hitbox.intersects(ray, max_distance) { return }
+        let Some((t1, t2)) = self.roots(ray) else { return };
+
+        // A ray that grazes the sphere only hits it once
+        let in_range = |t: f64| t > 0. && t <= max_distance;
+        if in_range(t1) { hits.push(self.collision_info(ray, t1)); }
+        if t2 > t1 && in_range(t2) { hits.push(self.collision_info(ray, t2)); }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        visitor.sphere(self.center, self.radius, &self.material, placement);
+    }
 }
 
 // Plane is not a SceneElement since that would require implementing an infinite
@@ -122,6 +247,11 @@ pub struct Triangle {
     normal: Vector,
     plane: Plane,
     hitbox: HitBox,
+    double_sided: bool,
+    // Vertex normals, interpolated for smooth shading
+    vertex_normals: Option<[Vector; 3]>,
+    // Texture coordinates of the vertices
+    vertex_uvs: Option<[(f64, f64); 3]>,
 
     // This cached data is used to improve efficiency in collision detection
     ac: Vector,
@@ -144,14 +274,30 @@ impl Triangle {
             Vector::new(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z))
         );
 
-        Self {a, b, material, normal, plane, hitbox, ac, bc, barycentric_a, barycentric_b}
+        let double_sided = material.double_sided();
+
+        Self {a, b, material, normal, plane, hitbox, double_sided, vertex_normals: None, vertex_uvs: None, ac, bc, barycentric_a, barycentric_b}
+    }
+
+    // Shades the triangle smoothly using the normals of its vertices, in the
+    // same order as the vertices
+    pub fn with_normals(mut self, normals: [Vector; 3]) -> Self {
+        self.vertex_normals = Some(normals);
+        self
+    }
+
+    // Sets the texture coordinates of the vertices, in the same order as the
+    // vertices. Without them, the barycentric coordinates of b and c are used
+    pub fn with_uvs(mut self, uvs: [(f64, f64); 3]) -> Self {
+        self.vertex_uvs = Some(uvs);
+        self
     }
 }
 
 impl SceneElement for Triangle {
     fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
         // Reject rays that face the back of the triangle
-        if ray.direction.dot(&self.normal) >= 0. { return None }
+        if !self.double_sided && ray.direction.dot(&self.normal) >= 0. { return None }
         if !self.hitbox.intersects(ray, max_distance) { return None }
 
         let distance = self.plane.collide(ray, max_distance)?;
@@ -172,10 +318,183 @@ impl SceneElement for Triangle {
         let c = 1. - a - b;
         if c < 0. { return None }
 
-        Some(CollisionInfo::new(distance, self.normal, self.material.clone()))
+        let uv = match &self.vertex_uvs {
+            Some([ua, ub, uc]) => (a * ua.0 + b * ub.0 + c * uc.0, a * ua.1 + b * ub.1 + c * uc.1),
+            None => (b, c)
+        };
+        let info = CollisionInfo::new(ray, distance, self.normal, self.material.clone())
+            .with_uv(uv)
+            .with_barycentric(Vector::new(a, b, c));
+
+        match &self.vertex_normals {
+            Some([na, nb, nc]) => {
+                let mut normal = (a * na + b * nb + c * nc).normalize();
+                // Keep the shading normal on the same side as the surface
+                if normal.dot(&self.normal) < 0. { normal = -normal; }
+                Some(info.with_shading_normal(normal))
+            },
+            None => Some(info)
+        }
     }
 
     fn hitbox(&self) -> &HitBox {
         &self.hitbox
     }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        let vertices = [self.a, self.b, self.a + self.ac];
+        visitor.triangle(vertices, self.vertex_normals, self.vertex_uvs, &self.material, placement);
+    }
+}
+
+// Applies a transform to any SceneElement. Rays are moved into the element's
+// space instead of moving the element, so it can be shared
+pub struct Transformed {
+    element: Arc<dyn SceneElement>,
+    transform: Transform,
+    hitbox: HitBox
+}
+
+impl Transformed {
+    pub fn new(element: Arc<dyn SceneElement>, transform: Transform) -> Self {
+        let hitbox = transform.hitbox(element.hitbox());
+        Self {element, transform, hitbox}
+    }
+}
+
+impl Transformed {
+    // Moves a hit in the element's space into world space
+    fn place(&self, info: &mut CollisionInfo) {
+        info.position = self.transform.point(&info.position);
+        info.normal = self.transform.normal(&info.normal);
+        info.geometric_normal = self.transform.normal(&info.geometric_normal);
+    }
+}
+
+impl SceneElement for Transformed {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        if !self.hitbox.intersects(ray, max_distance) { return None }
+
+        // The transformed ray isn't normalized, so distances along it are the
+        // same as in world space
+        let local = self.transform.inverse_ray(ray);
+        let mut info = self.element.collide(&local, max_distance)?;
+        self.place(&mut info);
+        Some(info)
+    }
+
+    fn hitbox(&self) -> &HitBox {
+        &self.hitbox
+    }
+
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.hitbox.intersects(ray, max_distance) && self.element.any_hit(&self.transform.inverse_ray(ray), max_distance)
+    }
+
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        let mask = set_bits(mask)
+            .filter(|&i| self.hitbox.intersects(&rays[i], closest[i]))
+            .fold(0, |mask, i| mask | 1 << i);
+        if mask == 0 { return }
+
+        let local: Vec<Ray> = rays.iter().map(|ray| self.transform.inverse_ray(ray)).collect();
+        let mut local_hits: Vec<Option<CollisionInfo>> = rays.iter().map(|_| None).collect();
+        self.element.collide_packet(&local, mask, closest, &mut local_hits);
+        for (hit, local_hit) in hits.iter_mut().zip(local_hits) {
+            if let Some(mut info) = local_hit {
+                self.place(&mut info);
+                *hit = Some(info);
+            }
+        }
+    }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        if !self.hitbox.intersects(ray, max_distance) { return }
+
+        let start = hits.len();
+        self.element.collect_hits(&self.transform.inverse_ray(ray), max_distance, hits);
+        for info in &mut hits[start..] {
+            self.place(info);
+        }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        let placement = Placement {
+            transform: placement.transform * self.transform,
+            material: placement.material.clone()
+        };
+        self.element.visit(visitor, &placement);
+    }
+}
+
+// A copy of a shared element, usually a BVH built with build_bvh, placed in
+// the scene with its own transform. All instances of an element share its
+// memory, and can optionally replace its material
+pub struct Instance {
+    object: Transformed,
+    material: Option<Arc<dyn Material>>
+}
+
+impl Instance {
+    pub fn new(object: Arc<dyn SceneElement>, transform: Transform) -> Self {
+        Self {
+            object: Transformed::new(object, transform),
+            material: None
+        }
+    }
+
+    pub fn with_material(mut self, material: Arc<dyn Material>) -> Self {
+        self.material = Some(material);
+        self
+    }
+}
+
+impl SceneElement for Instance {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        let mut info = self.object.collide(ray, max_distance)?;
+        if let Some(material) = &self.material {
+            info.material = material.clone();
+        }
+        Some(info)
+    }
+
+    fn hitbox(&self) -> &HitBox {
+        self.object.hitbox()
+    }
+
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.object.any_hit(ray, max_distance)
+    }
+
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        let Some(material) = &self.material else { return self.object.collide_packet(rays, mask, closest, hits) };
+
+        let mut own_hits: Vec<Option<CollisionInfo>> = rays.iter().map(|_| None).collect();
+        self.object.collide_packet(rays, mask, closest, &mut own_hits);
+        for (hit, own_hit) in hits.iter_mut().zip(own_hits) {
+            if let Some(mut info) = own_hit {
+                info.material = material.clone();
+                *hit = Some(info);
+            }
+        }
+    }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        let start = hits.len();
+        self.object.collect_hits(ray, max_distance, hits);
+        if let Some(material) = &self.material {
+            for info in &mut hits[start..] {
+                info.material = material.clone();
+            }
+        }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        // The material of an enclosing instance wins, like in collide
+        let placement = Placement {
+            transform: placement.transform,
+            material: placement.material.clone().or_else(|| self.material.clone())
+        };
+        self.object.visit(visitor, &placement);
+    }
 }
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use gltf::camera::Projection;
+use gltf::image::Format;
+use gltf::mesh::Mode;
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+use crate::geometry::hitbox::HitBox;
+use crate::geometry::transform::Transform;
+use crate::geometry::vector::Vector;
+use crate::scene::elements::{Instance, SceneElement};
+use crate::scene::loader::Scene;
+use crate::scene::materials::{Material, PbrMaterial, Texture, TwoSidedMaterial};
+use crate::scene::mesh::{Face, Mesh, MeshBuffers};
+use crate::config::{CameraSettings, RenderSettings, FOV_ANGLE};
+
+#[derive(Debug)]
+pub enum GltfError {
+    Import { path: PathBuf, error: gltf::Error },
+    Invalid { path: PathBuf, message: String }
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Import { path, error } => write!(f, "{}: {}", path.display(), error),
+            GltfError::Invalid { path, message } => write!(f, "{}: {}", path.display(), message)
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+// Reads the default scene of a .gltf or .glb file. Every mesh becomes a Mesh
+// shared by the nodes that use it, and the first perspective camera is used to
+// view the scene. Without one, the camera is placed in front of the scene
+pub fn read_gltf(path: impl AsRef<Path>) -> Result<Scene, GltfError> {
+    let path = path.as_ref();
+    let (document, buffers, images) = gltf::import(path).map_err(|error| GltfError::Import {
+        path: path.to_path_buf(),
+        error
+    })?;
+    let invalid = |message: String| GltfError::Invalid { path: path.to_path_buf(), message };
+
+    let scene = document.default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| invalid("the file has no scenes".to_string()))?;
+
+    let mut importer = Importer {
+        path,
+        buffers: &buffers,
+        images: &images,
+        meshes: HashMap::new(),
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+        elements: Vec::new(),
+        camera: None,
+        emissive: false
+    };
+    for node in scene.nodes() {
+        importer.node(&node, &Transform::IDENTITY).map_err(invalid)?;
+    }
+
+    let mut render = RenderSettings::default();
+    // Most glTF scenes are lit by lights caracol doesn't support rather than
+    // by emissive surfaces, so those without any get a white background
+    if !importer.emissive {
+        render.void = Vector::uniform(1.);
+    }
+
+    let camera = match importer.camera {
+        Some((mut camera, vertical_fov, aspect_ratio)) => {
+            if let Some(aspect_ratio) = aspect_ratio {
+                render.height = ((render.width as f64 / aspect_ratio) as u32).max(1);
+            }
+            // glTF gives the vertical field of view, and caracol uses the
+            // horizontal one
+            camera.fov_angle = 2. * ((vertical_fov / 2.).tan() * render.aspect_ratio()).atan();
+            camera
+        },
+        None => frame(&importer.elements, &render)
+    };
+
+    Ok(Scene { render, camera, elements: importer.elements })
+}
+
+struct Importer<'a> {
+    path: &'a Path,
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    // Converted meshes, materials and textures by index, so that they are
+    // shared. Meshes without triangles and unsupported textures are None
+    meshes: HashMap<usize, Option<Arc<dyn SceneElement>>>,
+    materials: HashMap<Option<usize>, Arc<dyn Material>>,
+    textures: HashMap<usize, Option<Arc<Texture>>>,
+
+    elements: Vec<Arc<dyn SceneElement>>,
+    // The first camera found, with its vertical field of view and aspect ratio
+    camera: Option<(CameraSettings, f64, Option<f64>)>,
+    // Whether any material emits light
+    emissive: bool
+}
+
+impl Importer<'_> {
+    // Adds a node and its children. parent is the transform of the node's
+    // parent relative to the scene
+    fn node(&mut self, node: &gltf::Node, parent: &Transform) -> Result<(), String> {
+        let Some(local) = Transform::new(transpose(node.transform().matrix())) else {
+            eprintln!("{}: skipping node {} since its transform can't be inverted", self.path.display(), node.index());
+            return Ok(());
+        };
+        let transform = *parent * local;
+
+        if let Some(mesh) = node.mesh() {
+            let element = match self.meshes.get(&mesh.index()) {
+                Some(element) => element.clone(),
+                None => {
+                    let element = self.mesh(&mesh)?;
+                    self.meshes.insert(mesh.index(), element.clone());
+                    element
+                }
+            };
+            if let Some(element) = element {
+                self.elements.push(Arc::new(Instance::new(element, transform)));
+            }
+        }
+
+        if let Some(camera) = node.camera() && self.camera.is_none() {
+            self.camera(&camera, &transform);
+        }
+
+        for child in node.children() {
+            self.node(&child, &transform)?;
+        }
+        Ok(())
+    }
+
+    // Builds a single Mesh out of all the triangle primitives of a mesh
+    fn mesh(&mut self, mesh: &gltf::Mesh) -> Result<Option<Arc<dyn SceneElement>>, String> {
+        let mut buffers = MeshBuffers::default();
+        let mut faces: Vec<Face> = Vec::new();
+        let mut materials: Vec<Arc<dyn Material>> = Vec::new();
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                eprintln!("{}: skipping a primitive of mesh {} that isn't made of triangles", self.path.display(), mesh.index());
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else { continue };
+
+            let offset = buffers.vertices.len() as u32;
+            buffers.vertices.extend(positions.map(vector));
+            let count = buffers.vertices.len() as u32 - offset;
+
+            let normal_offset = buffers.normals.len() as u32;
+            if let Some(normals) = reader.read_normals() {
+                buffers.normals.extend(normals.map(|normal| vector(normal).normalize()));
+            }
+            let has_normals = buffers.normals.len() as u32 - normal_offset == count;
+
+            // Only the texture coordinates used by the base color texture are
+            // read. glTF places v = 0 at the top of the image, unlike .obj
+            let material = primitive.material();
+            let set = material.pbr_metallic_roughness().base_color_texture().map_or(0, |info| info.tex_coord());
+            let uv_offset = buffers.uvs.len() as u32;
+            if let Some(uvs) = reader.read_tex_coords(set) {
+                buffers.uvs.extend(uvs.into_f32().map(|[u, v]| (u as f64, 1. - v as f64)));
+            }
+            let has_uvs = buffers.uvs.len() as u32 - uv_offset == count;
+
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..count).collect()
+            };
+            if let Some(index) = indices.iter().find(|&&index| index >= count) {
+                return Err(format!("mesh {} uses vertex {}, but it only has {}", mesh.index(), index, count));
+            }
+
+            materials.push(self.material(&material));
+            let material = materials.len() as u32 - 1;
+            for triangle in indices.chunks_exact(3) {
+                let triangle = [triangle[0], triangle[1], triangle[2]];
+                faces.push(Face {
+                    vertices: triangle.map(|i| offset + i),
+                    normals: has_normals.then(|| triangle.map(|i| normal_offset + i)),
+                    uvs: has_uvs.then(|| triangle.map(|i| uv_offset + i)),
+                    material
+                });
+            }
+        }
+
+        if faces.is_empty() { return Ok(None) }
+        Ok(Some(Arc::new(Mesh::new(Arc::new(buffers), faces, materials))))
+    }
+
+    fn material(&mut self, material: &gltf::Material) -> Arc<dyn Material> {
+        if let Some(converted) = self.materials.get(&material.index()) {
+            return converted.clone();
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let emission = vector(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.) as f64;
+        if !emission.is_zero() { self.emissive = true; }
+
+        let mut converted = PbrMaterial::new(
+            Vector::new(r as f64, g as f64, b as f64),
+            pbr.metallic_factor() as f64,
+            pbr.roughness_factor() as f64
+        )
+            .with_emission(emission);
+        if let Some(info) = pbr.base_color_texture()
+            && let Some(texture) = self.texture(info.texture().source().index()) {
+            converted = converted.with_texture(texture);
+        }
+
+        // Back faces of double sided materials are shaded like front faces
+        let converted: Arc<dyn Material> = if material.double_sided() {
+            Arc::new(TwoSidedMaterial::new(Arc::new(converted)))
+        } else {
+            Arc::new(converted)
+        };
+        self.materials.insert(material.index(), converted.clone());
+        converted
+    }
+
+    fn texture(&mut self, index: usize) -> Option<Arc<Texture>> {
+        if let Some(texture) = self.textures.get(&index) {
+            return texture.clone();
+        }
+
+        let data = &self.images[index];
+        let (width, height, pixels) = (data.width, data.height, data.pixels.clone());
+        let image: Option<RgbImage> = match data.format {
+            Format::R8G8B8 => RgbImage::from_raw(width, height, pixels),
+            Format::R8G8B8A8 => RgbaImage::from_raw(width, height, pixels).map(|image| DynamicImage::ImageRgba8(image).to_rgb8()),
+            Format::R8 => GrayImage::from_raw(width, height, pixels).map(|image| DynamicImage::ImageLuma8(image).to_rgb8()),
+            _ => None
+        };
+        if image.is_none() {
+            eprintln!("{}: ignoring image {} since its format ({:?}) isn't supported", self.path.display(), index, data.format);
+        }
+
+        let texture = image.map(|image| Arc::new(Texture::new(&image)));
+        self.textures.insert(index, texture.clone());
+        texture
+    }
+
+    // Cameras look down their -Z axis, with +Y pointing up
+    fn camera(&mut self, camera: &gltf::Camera, transform: &Transform) {
+        let Projection::Perspective(perspective) = camera.projection() else {
+            eprintln!("{}: skipping camera {} since orthographic cameras aren't supported", self.path.display(), camera.index());
+            return;
+        };
+
+        let position = transform.point(&Vector::ZERO);
+        let forward = transform.vector(&Vector::new(0., 0., -1.)).normalize();
+        let settings = CameraSettings {
+            position,
+            look_at: position + forward,
+            view_up: transform.vector(&Vector::new(0., 1., 0.)),
+            fov_angle: FOV_ANGLE,
+            defocus_angle: 0.
+        };
+        self.camera = Some((settings, perspective.yfov() as f64, perspective.aspect_ratio().map(f64::from)));
+    }
+}
+
+// Places a camera in front of the elements and slightly above them, far
+// enough to fit all of them in view
+fn frame(elements: &[Arc<dyn SceneElement>], render: &RenderSettings) -> CameraSettings {
+    let Some(first) = elements.first() else { return CameraSettings::default() };
+    let mut bounds: HitBox = *first.hitbox();
+    for element in elements {
+        bounds.merge(element.hitbox());
+    }
+
+    let center = (bounds.start() + bounds.end()) / 2.;
+    let radius = (bounds.end() - bounds.start()).magnitude() / 2.;
+    let vertical_fov = 2. * ((FOV_ANGLE / 2.).tan() / render.aspect_ratio()).atan();
+    let distance = radius / (FOV_ANGLE.min(vertical_fov) / 2.).sin();
+
+    CameraSettings {
+        position: center + Vector::new(0., 1., 2.).normalize() * distance,
+        look_at: center,
+        view_up: Vector::new(0., 1., 0.),
+        fov_angle: FOV_ANGLE,
+        defocus_angle: 0.
+    }
+}
+
+fn vector([x, y, z]: [f32; 3]) -> Vector {
+    Vector::new(x as f64, y as f64, z as f64)
+}
+
+// glTF matrices are column-major
+fn transpose(matrix: [[f32; 4]; 4]) -> [[f64; 4]; 4] {
+    let mut result = [[0.; 4]; 4];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = matrix[j][i] as f64;
+        }
+    }
+    result
+}
@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::Deserialize;
+use toml::Spanned;
+use crate::config::{CameraSettings, RenderSettings};
+use crate::geometry::transform::Transform;
+use crate::geometry::vector::Vector;
+use crate::scene::elements::{Instance, SceneElement, Sphere, Transformed, Triangle};
+use crate::scene::mesh::Mesh;
+use crate::scene::gltf_loader::{read_gltf, GltfError};
+use crate::scene::pbrt_loader::{read_pbrt, PbrtError};
+use crate::scene::materials::{DielectricMaterial, DiffuseMaterial, Material, ReflectiveMaterial, Texture, TexturedMaterial, TwoSidedMaterial, VertexColorMaterial};
+use crate::utils::reader::{read_obj_transformed, read_obj_with_mtl, read_ply_transformed, read_stl_transformed, ObjError, PlyError, StlError};
+
+// A scene loaded from a scene file: the settings it describes and the elements
+// that can be handed to the Raytracer
+pub struct Scene {
+    pub render: RenderSettings,
+    pub camera: CameraSettings,
+    pub elements: Vec<Arc<dyn SceneElement>>
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, line: usize, message: String },
+    // An error in one of the meshes the scene loads
+    Obj(ObjError),
+    Ply(PlyError),
+    Stl(StlError),
+    Gltf(GltfError),
+    Pbrt(PbrtError)
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            SceneError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message),
+            SceneError::Obj(error) => write!(f, "{}", error),
+            SceneError::Ply(error) => write!(f, "{}", error),
+            SceneError::Stl(error) => write!(f, "{}", error),
+            SceneError::Gltf(error) => write!(f, "{}", error),
+            SceneError::Pbrt(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+// The layout of a scene file. Every section is optional, missing settings fall
+// back to the defaults in config.rs
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    render: RenderSection,
+    #[serde(default)]
+    camera: CameraSection,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialSection>>,
+    #[serde(default)]
+    meshes: Vec<MeshSection>,
+    #[serde(default)]
+    objects: HashMap<String, MeshSection>,
+    #[serde(default)]
+    instances: Vec<InstanceSection>,
+    #[serde(default)]
+    spheres: Vec<SphereSection>,
+    #[serde(default)]
+    triangles: Vec<TriangleSection>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightSection>>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderSection {
+    width: Option<NonZeroU32>,
+    height: Option<NonZeroU32>,
+    rays_per_pixel: Option<NonZeroU32>,
+    bounces: Option<u8>,
+    background: Option<[f64; 3]>,
+    seed: Option<u64>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CameraSection {
+    position: Option<[f64; 3]>,
+    look_at: Option<[f64; 3]>,
+    up: Option<[f64; 3]>,
+    // Angles are given in degrees
+    fov: Option<f64>,
+    defocus_angle: Option<f64>
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum MaterialSection {
+    Diffuse {
+        color: [f64; 3],
+        #[serde(default)]
+        emission: f64,
+        #[serde(default)]
+        two_sided: bool
+    },
+    Reflective {
+        color: [f64; 3],
+        #[serde(default)]
+        emission: f64,
+        glossiness: f64,
+        #[serde(default)]
+        two_sided: bool
+    },
+    Dielectric {
+        #[serde(default = "white")]
+        color: [f64; 3],
+        refraction_index: f64,
+        #[serde(default)]
+        two_sided: bool
+    },
+    Textured {
+        texture: String,
+        #[serde(default = "white")]
+        color: [f64; 3],
+        #[serde(default)]
+        emission: f64,
+        #[serde(default)]
+        two_sided: bool
+    },
+    #[serde(rename = "vertex_color")]
+    VertexColor {
+        #[serde(default = "white")]
+        color: [f64; 3],
+        #[serde(default)]
+        emission: f64,
+        #[serde(default)]
+        two_sided: bool
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshSection {
+    file: Spanned<String>,
+    // Required unless use_mtl is set, in which case it is used for faces
+    // without a known .mtl material
+    material: Option<Spanned<String>>,
+    #[serde(default)]
+    use_mtl: bool,
+    // Maps .mtl material names to materials of the scene
+    #[serde(default)]
+    material_overrides: HashMap<String, Spanned<String>>,
+    transform: Option<Spanned<TransformSection>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereSection {
+    center: [f64; 3],
+    radius: f64,
+    material: Spanned<String>,
+    transform: Option<Spanned<TransformSection>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TriangleSection {
+    vertices: [[f64; 3]; 3],
+    material: Spanned<String>,
+    transform: Option<Spanned<TransformSection>>
+}
+
+// An instance of one of the objects, which are meshes that are only rendered
+// through their instances
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstanceSection {
+    object: Spanned<String>,
+    material: Option<Spanned<String>>,
+    transform: Option<Spanned<TransformSection>>
+}
+
+// Transforms are applied in order: scale, rotation around X, Y and Z (in
+// degrees), and translation
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransformSection {
+    scale: Option<ScaleSection>,
+    rotate: Option<[f64; 3]>,
+    translate: Option<[f64; 3]>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScaleSection {
+    Uniform(f64),
+    Axes([f64; 3])
+}
+
+// Lights are emissive geometry: either a quad or a sphere
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightSection {
+    #[serde(default = "white")]
+    color: [f64; 3],
+    intensity: f64,
+    quad: Option<[[f64; 3]; 4]>,
+    sphere: Option<LightSphere>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightSphere {
+    center: [f64; 3],
+    radius: f64
+}
+
+fn white() -> [f64; 3] {
+    [1., 1., 1.]
+}
+
+fn vector(v: [f64; 3]) -> Vector {
+    Vector::new(v[0], v[1], v[2])
+}
+
+// Loads a scene file, a .gltf or .glb file, or a .pbrt file
+pub fn load_scene(path: impl AsRef<Path>) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    if extension == "gltf" || extension == "glb" {
+        return read_gltf(path).map_err(SceneError::Gltf);
+    }
+    if extension == "pbrt" {
+        return read_pbrt(path).map_err(SceneError::Pbrt);
+    }
+
+    let source = fs::read_to_string(path).map_err(|error| SceneError::Io {
+        path: path.to_path_buf(),
+        error
+    })?;
+    parse_scene(&source, path)
+}
+
+// Parses the contents of a scene file. Relative paths inside it are resolved
+// against the directory of `path`
+pub fn parse_scene(source: &str, path: &Path) -> Result<Scene, SceneError> {
+    let error = |offset: usize, message: String| SceneError::Parse {
+        path: path.to_path_buf(),
+        line: line_number(source, offset),
+        message
+    };
+
+    let file: SceneFile = toml::from_str(source).map_err(|e| {
+        let offset = e.span().map(|span| span.start).unwrap_or(0);
+        error(offset, e.message().to_string())
+    })?;
+
+    let render = render_settings(&file.render);
+    let camera = camera_settings(&file.camera);
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut materials: HashMap<&str, Arc<dyn Material>> = HashMap::new();
+    for (name, material) in &file.materials {
+        let built = build_material(material.get_ref(), directory)
+            .map_err(|message| error(material.span().start, message))?;
+        materials.insert(name.as_str(), built);
+    }
+
+    let material = |name: &Spanned<String>| -> Result<Arc<dyn Material>, SceneError> {
+        materials.get(name.get_ref().as_str()).cloned().ok_or_else(|| {
+            error(name.span().start, format!("unknown material `{}`", name.get_ref()))
+        })
+    };
+
+    let mut elements: Vec<Arc<dyn SceneElement>> = Vec::new();
+
+    let transform = |section: &Option<Spanned<TransformSection>>| -> Result<Transform, SceneError> {
+        match section {
+            Some(section) => build_transform(section.get_ref()).ok_or_else(|| {
+                error(section.span().start, "scale factors can't be zero".to_string())
+            }),
+            None => Ok(Transform::IDENTITY)
+        }
+    };
+
+    let mesh = |mesh: &MeshSection| -> Result<Mesh, SceneError> {
+        let transform = transform(&mesh.transform)?;
+        let file = directory.join(mesh.file.get_ref());
+        if !file.is_file() {
+            return Err(error(mesh.file.span().start, format!("mesh file `{}` not found", file.display())));
+        }
+        let filename = file.to_string_lossy();
+
+        let extension = file.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        if extension == "ply" || extension == "stl" {
+            if mesh.use_mtl {
+                return Err(error(mesh.file.span().start, "`use_mtl` only applies to .obj files".to_string()));
+            }
+            let material = match &mesh.material {
+                Some(name) => material(name)?,
+                None => return Err(error(mesh.file.span().start, "a mesh needs a material".to_string()))
+            };
+            return if extension == "ply" {
+                read_ply_transformed(&filename, material, &transform).map_err(SceneError::Ply)
+            } else {
+                read_stl_transformed(&filename, material, &transform).map_err(SceneError::Stl)
+            };
+        }
+
+        if !mesh.use_mtl {
+            let material = match &mesh.material {
+                Some(name) => material(name)?,
+                None => return Err(error(mesh.file.span().start, "a mesh needs a material unless `use_mtl` is set".to_string()))
+            };
+            return read_obj_transformed(&filename, material, &transform).map_err(SceneError::Obj);
+        }
+
+        let fallback = match &mesh.material {
+            Some(name) => material(name)?,
+            None => Arc::new(DiffuseMaterial::new(Vector::uniform(0.8), 0.))
+        };
+        let mut overrides: HashMap<String, Arc<dyn Material>> = HashMap::new();
+        for (mtl_name, name) in &mesh.material_overrides {
+            overrides.insert(mtl_name.clone(), material(name)?);
+        }
+        read_obj_with_mtl(&filename, fallback, &overrides, &transform).map_err(SceneError::Obj)
+    };
+
+    for section in &file.meshes {
+        elements.push(Arc::new(mesh(section)?));
+    }
+
+    // Each object is loaded once, no matter how many instances it has
+    let mut objects: HashMap<&str, Arc<dyn SceneElement>> = HashMap::new();
+    for instance in &file.instances {
+        let name = instance.object.get_ref().as_str();
+        let object = match objects.get(name) {
+            Some(object) => object.clone(),
+            None => {
+                let section = file.objects.get(name).ok_or_else(|| {
+                    error(instance.object.span().start, format!("unknown object `{}`", name))
+                })?;
+                let object: Arc<dyn SceneElement> = Arc::new(mesh(section)?);
+                objects.insert(name, object.clone());
+                object
+            }
+        };
+
+        let mut element = Instance::new(object, transform(&instance.transform)?);
+        if let Some(name) = &instance.material {
+            element = element.with_material(material(name)?);
+        }
+        elements.push(Arc::new(element));
+    }
+
+    for sphere in &file.spheres {
+        let material = material(&sphere.material)?;
+        let element = Arc::new(Sphere::new(vector(sphere.center), sphere.radius, material));
+        match &sphere.transform {
+            Some(_) => elements.push(Arc::new(Transformed::new(element, transform(&sphere.transform)?))),
+            None => elements.push(element)
+        }
+    }
+
+    for triangle in &file.triangles {
+        let material = material(&triangle.material)?;
+        let transform = transform(&triangle.transform)?;
+        let [a, mut b, mut c] = triangle.vertices.map(|v| transform.point(&vector(v)));
+        if transform.flips_orientation() { std::mem::swap(&mut b, &mut c); }
+        elements.push(Arc::new(Triangle::new(a, b, c, material)));
+    }
+
+    for light in &file.lights {
+        let span = light.span();
+        let light = light.get_ref();
+        let material: Arc<dyn Material> = Arc::new(DiffuseMaterial::new(vector(light.color), light.intensity));
+
+        match (&light.quad, &light.sphere) {
+            (Some(quad), None) => {
+                let [a, b, c, d] = quad.map(vector);
+                elements.push(Arc::new(Triangle::new(a, b, c, material.clone())));
+                elements.push(Arc::new(Triangle::new(a, c, d, material)));
+            },
+            (None, Some(sphere)) => {
+                elements.push(Arc::new(Sphere::new(vector(sphere.center), sphere.radius, material)));
+            },
+            _ => return Err(error(span.start, "a light needs exactly one of `quad` or `sphere`".to_string()))
+        }
+    }
+
+    Ok(Scene { render, camera, elements })
+}
+
+fn render_settings(render: &RenderSection) -> RenderSettings {
+    let mut settings = RenderSettings::default();
+
+    settings.resize(render.width.map(NonZeroU32::get), render.height.map(NonZeroU32::get));
+    if let Some(rays_per_pixel) = render.rays_per_pixel { settings.rays_per_pixel = rays_per_pixel.get(); }
+    if let Some(bounces) = render.bounces { settings.bounces = bounces; }
+    if let Some(background) = render.background { settings.void = vector(background); }
+    if render.seed.is_some() { settings.seed = render.seed; }
+
+    settings
+}
+
+fn camera_settings(camera: &CameraSection) -> CameraSettings {
+    let mut settings = CameraSettings::default();
+
+    if let Some(position) = camera.position { settings.position = vector(position); }
+    if let Some(look_at) = camera.look_at { settings.look_at = vector(look_at); }
+    if let Some(up) = camera.up { settings.view_up = vector(up); }
+    if let Some(fov) = camera.fov { settings.fov_angle = fov.to_radians(); }
+    if let Some(defocus_angle) = camera.defocus_angle { settings.defocus_angle = defocus_angle.to_radians(); }
+
+    settings
+}
+
+// Returns None if the transform can't be inverted
+fn build_transform(section: &TransformSection) -> Option<Transform> {
+    let mut transform = Transform::IDENTITY;
+
+    if let Some(scale) = &section.scale {
+        let factors = match scale {
+            ScaleSection::Uniform(factor) => Vector::uniform(*factor),
+            ScaleSection::Axes(factors) => vector(*factors)
+        };
+        if factors.x == 0. || factors.y == 0. || factors.z == 0. { return None }
+        transform = transform.then(&Transform::scaling(factors));
+    }
+
+    if let Some([x, y, z]) = section.rotate {
+        transform = transform
+            .then(&Transform::rotation_x(x.to_radians()))
+            .then(&Transform::rotation_y(y.to_radians()))
+            .then(&Transform::rotation_z(z.to_radians()));
+    }
+
+    if let Some(translate) = section.translate {
+        transform = transform.then(&Transform::translation(vector(translate)));
+    }
+
+    Some(transform)
+}
+
+fn build_material(material: &MaterialSection, directory: &Path) -> Result<Arc<dyn Material>, String> {
+    let (built, two_sided): (Arc<dyn Material>, bool) = match material {
+        MaterialSection::Diffuse { color, emission, two_sided } => {
+            (Arc::new(DiffuseMaterial::new(vector(*color), *emission)), *two_sided)
+        },
+        MaterialSection::Reflective { color, emission, glossiness, two_sided } => {
+            (Arc::new(ReflectiveMaterial::new(vector(*color), *emission, *glossiness)), *two_sided)
+        },
+        MaterialSection::Dielectric { color, refraction_index, two_sided } => {
+            (Arc::new(DielectricMaterial::new(vector(*color), *refraction_index)), *two_sided)
+        },
+        MaterialSection::Textured { texture, color, emission, two_sided } => {
+            let path = directory.join(texture);
+            let texture = Texture::open(&path)
+                .map_err(|e| format!("couldn't read texture `{}`: {}", path.display(), e))?;
+            (Arc::new(TexturedMaterial::new(Arc::new(texture), vector(*color), *emission)), *two_sided)
+        },
+        MaterialSection::VertexColor { color, emission, two_sided } => {
+            (Arc::new(VertexColorMaterial::new(vector(*color), *emission)), *two_sided)
+        }
+    };
+    Ok(if two_sided { Arc::new(TwoSidedMaterial::new(built)) } else { built })
+}
+
+// Converts a byte offset into a 1-indexed line number
+fn line_number(source: &str, offset: usize) -> usize {
+    let offset = offset.min(source.len());
+    source.as_bytes()[..offset].iter().filter(|&&c| c == b'\n').count() + 1
+}
@@ -1,10 +1,37 @@
+use std::path::Path;
+use std::sync::Arc;
+use image::{Rgb, RgbImage};
 use crate::geometry::vector::Vector;
 use crate::geometry::ray::Ray;
+use crate::scene::elements::CollisionInfo;
+use crate::utils::utils::random;
+use crate::config::EPSILON;
 
+// Materials may vary over a surface, so their colors depend on where they are
+// hit
 pub trait Material: Send + Sync {
-    fn albedo(&self) -> Vector;
-    fn emission(&self) -> Vector;
+    fn albedo(&self, info: &CollisionInfo) -> Vector;
+    fn emission(&self, info: &CollisionInfo) -> Vector;
     fn reflect(&self, ray: &Ray, point: &Vector, normal: &Vector) -> Ray;
+    // The material's settings, for exporters
+    fn description(&self) -> MaterialDescription<'_>;
+
+    // Whether rays can hit the back of triangles using this material
+    fn double_sided(&self) -> bool {
+        false
+    }
+}
+
+// The settings of each kind of material
+pub enum MaterialDescription<'a> {
+    Diffuse { color: Vector, emission: f64 },
+    Reflective { color: Vector, emission: f64, glossiness: f64 },
+    Dielectric { color: Vector, refraction_index: f64 },
+    Textured { texture: &'a Arc<Texture>, tint: Vector, emission: f64 },
+    VertexColor { tint: Vector, emission: f64 },
+    Pbr { base_color: Vector, texture: Option<&'a Arc<Texture>>, metallic: f64, roughness: f64, emission: Vector },
+    // A material whose back faces are shaded like its front faces
+    TwoSided(&'a dyn Material)
 }
 
 pub struct DiffuseMaterial {
@@ -19,24 +46,32 @@ impl DiffuseMaterial {
 }
 
 impl Material for DiffuseMaterial {
-    fn albedo(&self) -> Vector {
-        return self.albedo;
+    fn albedo(&self, _: &CollisionInfo) -> Vector {
+        self.albedo
     }
 
-    fn emission(&self) -> Vector {
+    fn emission(&self, _: &CollisionInfo) -> Vector {
         self.albedo * self.intensity
     }
 
     fn reflect(&self, _: &Ray, point: &Vector, normal: &Vector) -> Ray {
-        // Diffuse materials use Lambertian distribution
-        let mut direction = normal + Vector::random();
-        // Prevent the unlikely event that the result of the above operation is 0
-        if direction.is_zero() { direction = normal.clone(); }
+        diffuse_bounce(point, normal)
+    }
 
-        Ray::new(point.clone(), direction)
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::Diffuse { color: self.albedo, emission: self.intensity }
     }
 }
 
+// Bounces a ray off a diffuse surface, with a Lambertian distribution
+fn diffuse_bounce(point: &Vector, normal: &Vector) -> Ray {
+    let mut direction = normal + Vector::random();
+    // Prevent the unlikely event that the result of the above operation is 0
+    if direction.is_zero() { direction = *normal; }
+
+    Ray::new(*point, direction)
+}
+
 pub struct ReflectiveMaterial {
     pub albedo: Vector,
     pub glossiness: f64,
@@ -50,21 +85,316 @@ impl ReflectiveMaterial {
 }
 
 impl Material for ReflectiveMaterial {
-    fn albedo(&self) -> Vector {
-        return self.albedo;
+    fn albedo(&self, _: &CollisionInfo) -> Vector {
+        self.albedo
     }
     
-    fn emission(&self) -> Vector {
+    fn emission(&self, _: &CollisionInfo) -> Vector {
         self.albedo * self.intensity
     }
 
     fn reflect(&self, ray: &Ray, point: &Vector, normal: &Vector) -> Ray {
-        let mut direction = ray.direction - 2. * ray.direction.dot(&normal) * normal;
+        let mut direction = ray.direction - 2. * ray.direction.dot(normal) * normal;
         direction += (1. - self.glossiness) * Vector::random();
 
         // Prevent the unlikely event that the result of the above operation is 0
-        if direction.is_zero() { direction = normal.clone(); }
+        if direction.is_zero() { direction = *normal; }
+
+        Ray::new(*point, direction)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::Reflective { color: self.albedo, emission: self.intensity, glossiness: self.glossiness }
+    }
+}
+
+// Glass-like material that both reflects and refracts light
+// https://raytracing.github.io/books/RayTracingInOneWeekend.html#dielectrics
+pub struct DielectricMaterial {
+    pub albedo: Vector,
+    pub refraction_index: f64
+}
+
+impl DielectricMaterial {
+    pub fn new(albedo: Vector, refraction_index: f64) -> Self {
+        Self { albedo, refraction_index }
+    }
+
+    // Schlick's approximation of the reflectance
+    fn reflectance(cosine: f64, ratio: f64) -> f64 {
+        let r0 = ((1. - ratio) / (1. + ratio)).powi(2);
+        r0 + (1. - r0) * (1. - cosine).powi(5)
+    }
+}
+
+impl Material for DielectricMaterial {
+    fn albedo(&self, _: &CollisionInfo) -> Vector {
+        self.albedo
+    }
+
+    fn emission(&self, _: &CollisionInfo) -> Vector {
+        Vector::ZERO
+    }
+
+    fn reflect(&self, ray: &Ray, point: &Vector, normal: &Vector) -> Ray {
+        let unit = ray.direction.normalize();
+
+        // The normal always points outwards, so rays leaving the material
+        // travel along it
+        let entering = unit.dot(normal) < 0.;
+        let (facing, ratio) = if entering {
+            (*normal, 1. / self.refraction_index)
+        } else {
+            (-*normal, self.refraction_index)
+        };
+
+        let cos = (-unit.dot(&facing)).min(1.);
+        let sin = (1. - cos * cos).sqrt();
+
+        let direction = if ratio * sin > 1. || Self::reflectance(cos, ratio) > random::<f64>() {
+            unit - 2. * unit.dot(&facing) * facing
+        } else {
+            let perpendicular = ratio * (unit + cos * facing);
+            let parallel = -(1. - perpendicular.magnitude_sqr()).abs().sqrt() * facing;
+            perpendicular + parallel
+        };
+
+        // point was moved off the surface along the normal. Rays that go
+        // through the surface must start on its other side
+        let origin = if direction.dot(normal) < 0. { point - 2. * EPSILON * normal } else { *point };
+        Ray::new(origin, direction)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::Dielectric { color: self.albedo, refraction_index: self.refraction_index }
+    }
+
+    fn double_sided(&self) -> bool {
+        true
+    }
+}
+
+// An image that can be sampled with texture coordinates
+pub struct Texture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector>
+}
+
+impl Texture {
+    pub fn new(image: &RgbImage) -> Self {
+        let pixels = image.pixels()
+            .map(|p| Vector::new(p[0] as f64, p[1] as f64, p[2] as f64) / 255.)
+            .collect();
+        Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels
+        }
+    }
+
+    pub fn open(path: &Path) -> image::ImageResult<Self> {
+        Ok(Self::new(&image::open(path)?.to_rgb8()))
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let pixel = self.pixels[y as usize * self.width + x as usize];
+            Rgb([pixel.x, pixel.y, pixel.z].map(|c| (c * 255.).round().clamp(0., 255.) as u8))
+        })
+    }
+
+    // Returns the bilinearly filtered color at (u, v). The texture repeats
+    // outside of [0, 1], and v goes from the bottom of the image to the top
+    pub fn sample(&self, (u, v): (f64, f64)) -> Vector {
+        if self.pixels.is_empty() { return Vector::ZERO }
+
+        let x = u.rem_euclid(1.) * self.width as f64 - 0.5;
+        let y = (1. - v.rem_euclid(1.)) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+
+        let pixel = |x: f64, y: f64| {
+            let x = (x as i64).rem_euclid(self.width as i64) as usize;
+            let y = (y as i64).rem_euclid(self.height as i64) as usize;
+            self.pixels[y * self.width + x]
+        };
+
+        let top = pixel(x0, y0) * (1. - tx) + pixel(x0 + 1., y0) * tx;
+        let bottom = pixel(x0, y0 + 1.) * (1. - tx) + pixel(x0 + 1., y0 + 1.) * tx;
+        top * (1. - ty) + bottom * ty
+    }
+}
+
+// A diffuse material whose albedo is given by a texture, multiplied by tint
+pub struct TexturedMaterial {
+    pub texture: Arc<Texture>,
+    pub tint: Vector,
+    pub intensity: f64
+}
+
+impl TexturedMaterial {
+    pub fn new(texture: Arc<Texture>, tint: Vector, intensity: f64) -> Self {
+        Self { texture, tint, intensity }
+    }
+}
+
+impl Material for TexturedMaterial {
+    fn albedo(&self, info: &CollisionInfo) -> Vector {
+        self.texture.sample(info.uv).hadamard(&self.tint)
+    }
+
+    fn emission(&self, info: &CollisionInfo) -> Vector {
+        self.albedo(info) * self.intensity
+    }
+
+    fn reflect(&self, _: &Ray, point: &Vector, normal: &Vector) -> Ray {
+        diffuse_bounce(point, normal)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::Textured { texture: &self.texture, tint: self.tint, emission: self.intensity }
+    }
+}
+
+// A diffuse material colored by the vertex colors of meshes, multiplied by
+// tint
+pub struct VertexColorMaterial {
+    pub tint: Vector,
+    pub intensity: f64
+}
+
+impl VertexColorMaterial {
+    pub fn new(tint: Vector, intensity: f64) -> Self {
+        Self { tint, intensity }
+    }
+}
+
+impl Material for VertexColorMaterial {
+    fn albedo(&self, info: &CollisionInfo) -> Vector {
+        info.color.hadamard(&self.tint)
+    }
+
+    fn emission(&self, info: &CollisionInfo) -> Vector {
+        self.albedo(info) * self.intensity
+    }
+
+    fn reflect(&self, _: &Ray, point: &Vector, normal: &Vector) -> Ray {
+        diffuse_bounce(point, normal)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::VertexColor { tint: self.tint, emission: self.intensity }
+    }
+}
+
+// Metallic-roughness material, as used by glTF. Metals reflect rays like
+// ReflectiveMaterial, blurrier the rougher they are, and everything else is
+// diffuse. Partially metallic surfaces pick one of the two at random
+pub struct PbrMaterial {
+    pub base_color: Vector,
+    pub texture: Option<Arc<Texture>>,
+    pub metallic: f64,
+    pub roughness: f64,
+    pub emission: Vector
+}
+
+impl PbrMaterial {
+    pub fn new(base_color: Vector, metallic: f64, roughness: f64) -> Self {
+        Self {
+            base_color,
+            texture: None,
+            metallic: metallic.clamp(0., 1.),
+            roughness: roughness.clamp(0., 1.),
+            emission: Vector::ZERO
+        }
+    }
+
+    // Multiplies the base color by a texture
+    pub fn with_texture(mut self, texture: Arc<Texture>) -> Self {
+        self.texture = Some(texture);
+        self
+    }
+
+    pub fn with_emission(mut self, emission: Vector) -> Self {
+        self.emission = emission;
+        self
+    }
+}
+
+impl Material for PbrMaterial {
+    fn albedo(&self, info: &CollisionInfo) -> Vector {
+        match &self.texture {
+            Some(texture) => texture.sample(info.uv).hadamard(&self.base_color),
+            None => self.base_color
+        }
+    }
+
+    fn emission(&self, _: &CollisionInfo) -> Vector {
+        self.emission
+    }
+
+    fn reflect(&self, ray: &Ray, point: &Vector, normal: &Vector) -> Ray {
+        if random::<f64>() >= self.metallic {
+            return diffuse_bounce(point, normal);
+        }
+
+        let unit = ray.direction.normalize();
+        let mut direction = unit - 2. * unit.dot(normal) * normal + self.roughness * Vector::random();
+        // Prevent the unlikely event that the result of the above operation is 0
+        if direction.is_zero() { direction = *normal; }
+
+        Ray::new(*point, direction)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::Pbr {
+            base_color: self.base_color,
+            texture: self.texture.as_ref(),
+            metallic: self.metallic,
+            roughness: self.roughness,
+            emission: self.emission
+        }
+    }
+}
+
+// Makes both sides of triangles behave like the front of material, for
+// formats where faces have no front side, such as pbrt scenes
+pub struct TwoSidedMaterial {
+    pub material: Arc<dyn Material>
+}
+
+impl TwoSidedMaterial {
+    pub fn new(material: Arc<dyn Material>) -> Self {
+        Self { material }
+    }
+}
+
+impl Material for TwoSidedMaterial {
+    fn albedo(&self, info: &CollisionInfo) -> Vector {
+        self.material.albedo(info)
+    }
+
+    fn emission(&self, info: &CollisionInfo) -> Vector {
+        self.material.emission(info)
+    }
+
+    fn reflect(&self, ray: &Ray, point: &Vector, normal: &Vector) -> Ray {
+        if ray.direction.dot(normal) < 0. {
+            return self.material.reflect(ray, point, normal);
+        }
+
+        // point was moved off the surface along the normal, which is the side
+        // the ray came from on the back of the surface
+        let normal = -*normal;
+        self.material.reflect(ray, &(point + 2. * EPSILON * normal), &normal)
+    }
+
+    fn description(&self) -> MaterialDescription<'_> {
+        MaterialDescription::TwoSided(self.material.as_ref())
+    }
 
-        Ray::new(point.clone(), direction)
+    fn double_sided(&self) -> bool {
+        true
     }
 }
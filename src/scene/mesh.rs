@@ -0,0 +1,427 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use crate::geometry::hitbox::HitBox;
+use crate::geometry::vector::Vector;
+use crate::geometry::ray::Ray;
+use crate::config::BvhSettings;
+use crate::scene::bvh::{BvhStats, BvhTree};
+use crate::scene::sbvh::clip_triangle;
+use crate::scene::elements::{CollisionInfo, ElementVisitor, Placement, SceneElement};
+use crate::scene::materials::Material;
+use crate::utils::utils::set_bits;
+
+// Vertex data of a mesh. Several meshes can share the same buffers, for
+// example the groups of an .obj file
+#[derive(Default)]
+pub struct MeshBuffers {
+    pub vertices: Vec<Vector>,
+    pub normals: Vec<Vector>,
+    pub uvs: Vec<(f64, f64)>,
+    // Either empty or one color per vertex
+    pub colors: Vec<Vector>
+}
+
+// A triangle of a mesh, given as indexes into the mesh's buffers and materials.
+// Faces are only smooth or textured if all their vertices have normals or
+// texture coordinates
+#[derive(Debug, Clone, Copy)]
+pub struct Face {
+    pub vertices: [u32; 3],
+    pub normals: Option<[u32; 3]>,
+    pub uvs: Option<[u32; 3]>,
+    pub material: u32
+}
+
+// A triangle mesh with shared vertices and its own BVH. It behaves like a BVH
+// of Triangles, but takes a fraction of the memory
+pub struct Mesh {
+    buffers: Arc<MeshBuffers>,
+    faces: Vec<Face>,
+    materials: Vec<Arc<dyn Material>>,
+    // Whether each material is double sided, to avoid asking on every face
+    double_sided: Vec<bool>,
+    bvh: BvhTree,
+    hitbox: HitBox,
+    // What the BVH is built again with when deform needs to, and the SAH cost
+    // it had when it was last built, once refitted
+    settings: BvhSettings,
+    built_cost: f64
+}
+
+// Why a mesh can't be deformed with the given buffers
+#[derive(Debug)]
+pub enum DeformError {
+    // A face refers to a vertex, normal or texture coordinate the buffers
+    // don't have
+    MissingData { face: usize, kind: &'static str },
+    // Vertex colors must be either missing or one per vertex
+    WrongColorCount { colors: usize, vertices: usize }
+}
+
+impl fmt::Display for DeformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeformError::MissingData { face, kind } => write!(f, "face {} refers to {} the buffers don't have", face, kind),
+            DeformError::WrongColorCount { colors, vertices } => write!(f, "{} vertex colors for {} vertices", colors, vertices)
+        }
+    }
+}
+
+impl std::error::Error for DeformError {}
+
+impl Mesh {
+    // Every index in faces must be valid for buffers and materials
+    pub fn new(buffers: Arc<MeshBuffers>, faces: Vec<Face>, materials: Vec<Arc<dyn Material>>) -> Self {
+        Self::with_settings(buffers, faces, materials, &BvhSettings::default())
+    }
+
+    // Like new, but builds the BVH with the given cost model
+    pub fn with_settings(buffers: Arc<MeshBuffers>, faces: Vec<Face>, materials: Vec<Arc<dyn Material>>, settings: &BvhSettings) -> Self {
+        let (bvh, faces) = build_tree(&buffers, faces, settings);
+        let built_cost = refitted_cost(&bvh, &buffers, &faces, settings);
+        let hitbox = bvh.hitbox();
+        let double_sided = materials.iter().map(|material| material.double_sided()).collect();
+
+        Self {buffers, faces, materials, double_sided, bvh, hitbox, settings: *settings, built_cost}
+    }
+
+    // Returns a copy of the mesh with its vertex data replaced by buffers, for
+    // example the next frame of an animation where only the vertices move.
+    // The BVH is refitted to the new vertices, and only built again when that
+    // makes its SAH cost grow past rebuild_threshold times its cost when it
+    // was built. Fails if the faces refer to data the buffers don't have
+    pub fn deform(&self, buffers: Arc<MeshBuffers>) -> Result<Self, DeformError> {
+        if !buffers.colors.is_empty() && buffers.colors.len() != buffers.vertices.len() {
+            return Err(DeformError::WrongColorCount { colors: buffers.colors.len(), vertices: buffers.vertices.len() });
+        }
+        for (i, face) in self.faces.iter().enumerate() {
+            let in_range = |indexes: Option<[u32; 3]>, count: usize| indexes.is_none_or(|indexes| indexes.iter().all(|&i| (i as usize) < count));
+            let kind = if !in_range(Some(face.vertices), buffers.vertices.len()) {
+                "vertices"
+            } else if !in_range(face.normals, buffers.normals.len()) {
+                "normals"
+            } else if !in_range(face.uvs, buffers.uvs.len()) {
+                "texture coordinates"
+            } else {
+                continue;
+            };
+            return Err(DeformError::MissingData { face: i, kind });
+        }
+
+        let hitboxes: Vec<HitBox> = self.faces.iter().map(|face| face_hitbox(&buffers, face)).collect();
+        let mut bvh = self.bvh.clone();
+        bvh.refit(&hitboxes);
+
+        let (bvh, faces, built_cost) = if bvh.sah_cost(&self.settings) > self.built_cost * self.settings.rebuild_threshold {
+            let (bvh, faces) = build_tree(&buffers, self.faces.clone(), &self.settings);
+            let cost = refitted_cost(&bvh, &buffers, &faces, &self.settings);
+            (bvh, faces, cost)
+        } else {
+            (bvh, self.faces.clone(), self.built_cost)
+        };
+
+        Ok(Self {
+            buffers,
+            faces,
+            materials: self.materials.clone(),
+            double_sided: self.double_sided.clone(),
+            hitbox: bvh.hitbox(),
+            bvh,
+            settings: self.settings,
+            built_cost
+        })
+    }
+
+    pub fn buffers(&self) -> &Arc<MeshBuffers> {
+        &self.buffers
+    }
+
+    pub fn faces(&self) -> &[Face] {
+        &self.faces
+    }
+
+    pub fn materials(&self) -> &[Arc<dyn Material>] {
+        &self.materials
+    }
+
+    // Statistics of the mesh's BVH, where elements are faces
+    pub fn bvh_stats(&self) -> BvhStats {
+        let mut stats = BvhStats::default();
+        self.bvh.stats(&mut stats, 0);
+        stats
+    }
+
+    // Writes the mesh and its BVH in the format read by read_binary. Materials
+    // aren't written, only how many there are
+    pub fn write_binary(&self, out: &mut impl Write) -> io::Result<()> {
+        let buffers = &self.buffers;
+        let counts = [
+            buffers.vertices.len(), buffers.normals.len(), buffers.uvs.len(), buffers.colors.len(),
+            self.faces.len(), self.materials.len()
+        ];
+        for count in counts {
+            out.write_all(&(count as u64).to_le_bytes())?;
+        }
+
+        for vector in buffers.vertices.iter().chain(&buffers.normals).chain(&buffers.colors) {
+            write_vector(out, vector)?;
+        }
+        for (u, v) in &buffers.uvs {
+            out.write_all(&u.to_le_bytes())?;
+            out.write_all(&v.to_le_bytes())?;
+        }
+
+        for face in &self.faces {
+            // Bit 0 tells whether the face has normals, and bit 1 whether it
+            // has texture coordinates
+            let flags = face.normals.is_some() as u8 | (face.uvs.is_some() as u8) << 1;
+            out.write_all(&[flags])?;
+            for index in face.vertices.iter().chain(&face.normals.unwrap_or_default()).chain(&face.uvs.unwrap_or_default()) {
+                out.write_all(&index.to_le_bytes())?;
+            }
+            out.write_all(&face.material.to_le_bytes())?;
+        }
+
+        self.bvh.write(out)
+    }
+
+    // Reads a mesh written by write_binary, which must have been written with
+    // as many materials as given. Malformed data is an InvalidData error
+    pub fn read_binary(input: &mut impl Read, materials: Vec<Arc<dyn Material>>) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut counts = [0; 6];
+        for count in &mut counts {
+            *count = read_u64(input)? as usize;
+        }
+        let [vertices, normals, uvs, colors, faces, material_count] = counts;
+        if material_count != materials.len() { return Err(invalid("wrong number of materials")) }
+        if colors != 0 && colors != vertices { return Err(invalid("wrong number of vertex colors")) }
+
+        // Counts aren't trusted to preallocate, reading past the end fails
+        // first if they are wrong
+        let mut buffers = MeshBuffers::default();
+        for _ in 0..vertices { buffers.vertices.push(read_vector(input)?); }
+        for _ in 0..normals { buffers.normals.push(read_vector(input)?); }
+        for _ in 0..colors { buffers.colors.push(read_vector(input)?); }
+        for _ in 0..uvs { buffers.uvs.push((read_f64(input)?, read_f64(input)?)); }
+
+        let mut face_list = Vec::new();
+        for _ in 0..faces {
+            let mut flags = [0];
+            input.read_exact(&mut flags)?;
+            let mut indexes = [0; 9];
+            for index in &mut indexes {
+                *index = read_u32(input)?;
+            }
+            let face = Face {
+                vertices: [indexes[0], indexes[1], indexes[2]],
+                normals: (flags[0] & 1 != 0).then_some([indexes[3], indexes[4], indexes[5]]),
+                uvs: (flags[0] & 2 != 0).then_some([indexes[6], indexes[7], indexes[8]]),
+                material: read_u32(input)?
+            };
+
+            let in_range = |indexes: Option<[u32; 3]>, count: usize| indexes.is_none_or(|indexes| indexes.iter().all(|&i| (i as usize) < count));
+            if !in_range(Some(face.vertices), vertices) || !in_range(face.normals, normals) || !in_range(face.uvs, uvs)
+                || face.material as usize >= material_count {
+                return Err(invalid("face index out of range"));
+            }
+            face_list.push(face);
+        }
+
+        let bvh = BvhTree::read(input, faces)?;
+        let settings = BvhSettings::default();
+        let built_cost = refitted_cost(&bvh, &buffers, &face_list, &settings);
+        let hitbox = bvh.hitbox();
+        let double_sided = materials.iter().map(|material| material.double_sided()).collect();
+        Ok(Self {
+            buffers: Arc::new(buffers),
+            faces: face_list,
+            materials,
+            double_sided,
+            bvh,
+            hitbox,
+            settings,
+            built_cost
+        })
+    }
+
+    // Möller-Trumbore intersection. Returns the distance and the barycentric
+    // coordinates of the second and third vertices
+    // https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
+    fn intersect(&self, face: &Face, ray: &Ray, max_distance: f64) -> Option<(f64, f64, f64)> {
+        let [a, b, c] = face.vertices.map(|i| self.buffers.vertices[i as usize]);
+        let ab = b - a;
+        let ac = c - a;
+
+        let p = ray.direction.cross(&ac);
+        let determinant = ab.dot(&p);
+        // A negative determinant means the ray faces the back of the triangle
+        if determinant < 0. && !self.double_sided[face.material as usize] { return None }
+        // Ray is parallel to the triangle
+        if determinant.abs() < 1e-12 { return None }
+
+        let inverse = 1. / determinant;
+        let t = ray.origin - a;
+        let u = t.dot(&p) * inverse;
+        if !(0. ..=1.).contains(&u) { return None }
+
+        let q = t.cross(&ab);
+        let v = ray.direction.dot(&q) * inverse;
+        if v < 0. || u + v > 1. { return None }
+
+        let distance = ac.dot(&q) * inverse;
+        if distance < 0. || distance > max_distance { return None }
+        Some((distance, u, v))
+    }
+
+    fn collision_info(&self, face: &Face, ray: &Ray, distance: f64, u: f64, v: f64) -> CollisionInfo {
+        let [a, b, c] = face.vertices.map(|i| self.buffers.vertices[i as usize]);
+        let normal = (c - a).cross(&(c - b)).normalize();
+        let w = 1. - u - v;
+
+        let uv = match face.uvs {
+            Some(uvs) => {
+                let [ua, ub, uc] = uvs.map(|i| self.buffers.uvs[i as usize]);
+                (w * ua.0 + u * ub.0 + v * uc.0, w * ua.1 + u * ub.1 + v * uc.1)
+            },
+            None => (u, v)
+        };
+        let mut info = CollisionInfo::new(ray, distance, normal, self.materials[face.material as usize].clone())
+            .with_uv(uv)
+            .with_barycentric(Vector::new(w, u, v));
+        if !self.buffers.colors.is_empty() {
+            let [ca, cb, cc] = face.vertices.map(|i| self.buffers.colors[i as usize]);
+            info = info.with_color(w * ca + u * cb + v * cc);
+        }
+
+        match face.normals {
+            Some(normals) => {
+                let [na, nb, nc] = normals.map(|i| self.buffers.normals[i as usize]);
+                let mut shading = (w * na + u * nb + v * nc).normalize();
+                // Keep the shading normal on the same side as the surface
+                if shading.dot(&normal) < 0. { shading = -shading; }
+                info.with_shading_normal(shading)
+            },
+            None => info
+        }
+    }
+}
+
+impl SceneElement for Mesh {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        let mut hit: Option<(usize, f64, f64)> = None;
+        let closest = self.bvh.traverse(ray, max_distance, |i, closest| {
+            let (distance, u, v) = self.intersect(&self.faces[i], ray, closest)?;
+            hit = Some((i, u, v));
+            Some(distance)
+        });
+
+        let (i, u, v) = hit?;
+        Some(self.collision_info(&self.faces[i], ray, closest, u, v))
+    }
+
+    fn hitbox(&self) -> &HitBox {
+        &self.hitbox
+    }
+
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        let mut faces: Vec<Option<(usize, f64, f64)>> = vec![None; rays.len()];
+        self.bvh.traverse_packet(rays, mask, closest, |i, active, closest| {
+            for ray in set_bits(active) {
+                if let Some((distance, u, v)) = self.intersect(&self.faces[i], &rays[ray], closest[ray]) {
+                    closest[ray] = distance;
+                    faces[ray] = Some((i, u, v));
+                }
+            }
+        });
+
+        for (ray, face) in faces.into_iter().enumerate() {
+            if let Some((i, u, v)) = face {
+                hits[ray] = Some(self.collision_info(&self.faces[i], &rays[ray], closest[ray], u, v));
+            }
+        }
+    }
+
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.bvh.any_hit(ray, max_distance, |i, _| self.intersect(&self.faces[i], ray, max_distance).is_some())
+    }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        let mut faces: Vec<(usize, f64, f64, f64)> = Vec::new();
+        self.bvh.traverse(ray, max_distance, |i, _| {
+            faces.extend(self.intersect(&self.faces[i], ray, max_distance).map(|(distance, u, v)| (i, distance, u, v)));
+            None
+        });
+
+        // Faces split by spatial splits are in several leaves
+        faces.sort_unstable_by_key(|&(i, ..)| i);
+        faces.dedup_by_key(|&mut (i, ..)| i);
+        for (i, distance, u, v) in faces {
+            hits.push(self.collision_info(&self.faces[i], ray, distance, u, v));
+        }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        visitor.mesh(self, placement);
+    }
+}
+
+// Builds a BVH over faces, and returns it with the faces in the order the
+// leaves use them, so that the faces of a leaf are next to each other
+fn build_tree(buffers: &MeshBuffers, faces: Vec<Face>, settings: &BvhSettings) -> (BvhTree, Vec<Face>) {
+    let hitboxes: Vec<HitBox> = faces.iter().map(|face| face_hitbox(buffers, face)).collect();
+    let mut bvh = BvhTree::build_clipped(&hitboxes, settings, |i, bounds| {
+        clip_triangle(faces[i].vertices.map(|i| buffers.vertices[i as usize]), bounds)
+    });
+    let faces = bvh.sort_items().into_iter().map(|i| faces[i as usize]).collect();
+    (bvh, faces)
+}
+
+// The SAH cost of bvh once refitted to the faces. Refitting gives faces
+// clipped by spatial splits their whole hitbox back, so this is what deform
+// compares refitted BVHs with
+fn refitted_cost(bvh: &BvhTree, buffers: &MeshBuffers, faces: &[Face], settings: &BvhSettings) -> f64 {
+    let hitboxes: Vec<HitBox> = faces.iter().map(|face| face_hitbox(buffers, face)).collect();
+    let mut bvh = bvh.clone();
+    bvh.refit(&hitboxes);
+    bvh.sah_cost(settings)
+}
+
+fn face_hitbox(buffers: &MeshBuffers, face: &Face) -> HitBox {
+    let [a, b, c] = face.vertices.map(|i| buffers.vertices[i as usize]);
+    HitBox::new(
+        Vector::new(a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y), a.z.min(b.z).min(c.z)),
+        Vector::new(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z))
+    )
+}
+
+fn write_vector(out: &mut impl Write, vector: &Vector) -> io::Result<()> {
+    for component in [vector.x, vector.y, vector.z] {
+        out.write_all(&component.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_vector(input: &mut impl Read) -> io::Result<Vector> {
+    Ok(Vector::new(read_f64(input)?, read_f64(input)?, read_f64(input)?))
+}
+
+fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -1,3 +1,12 @@
 pub mod bvh;
+pub mod sbvh;
+pub mod tlas;
+#[cfg(feature = "wide-bvh")]
+pub mod wide_bvh;
 pub mod materials;
 pub mod elements;
+pub mod mesh;
+pub mod loader;
+pub mod gltf_loader;
+pub mod pbrt_loader;
+pub mod cache;
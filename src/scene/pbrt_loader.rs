@@ -0,0 +1,710 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::config::{CameraSettings, RenderSettings};
+use crate::geometry::transform::Transform;
+use crate::geometry::vector::Vector;
+use crate::scene::bvh::build_bvh;
+use crate::scene::elements::{Instance, SceneElement, Sphere, Transformed};
+use crate::scene::loader::Scene;
+use crate::scene::materials::{DielectricMaterial, DiffuseMaterial, Material, ReflectiveMaterial, TwoSidedMaterial};
+use crate::scene::mesh::{Face, Mesh, MeshBuffers};
+use crate::utils::reader::read_ply_transformed;
+
+#[derive(Debug)]
+pub enum PbrtError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, line: usize, message: String }
+}
+
+impl fmt::Display for PbrtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PbrtError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            PbrtError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message)
+        }
+    }
+}
+
+impl std::error::Error for PbrtError {}
+
+// Reads a subset of the pbrt-v4 scene format: perspective cameras, the film
+// resolution, sample count, transforms, triangle meshes, spheres, .ply meshes,
+// diffuse, conductor and dielectric materials and diffuse area lights.
+// Anything else is skipped with a warning
+// https://pbrt.org/fileformat-v4
+pub fn read_pbrt(path: impl AsRef<Path>) -> Result<Scene, PbrtError> {
+    let path = path.as_ref();
+    let mut builder = Builder::new(path.parent().unwrap_or(Path::new("")));
+    builder.include(path)?;
+    Ok(builder.finish())
+}
+
+// A token of a pbrt file. Brackets are unquoted tokens of their own
+struct Token {
+    text: String,
+    quoted: bool,
+    line: usize
+}
+
+impl Token {
+    fn is_number(&self) -> bool {
+        !self.quoted && self.text.parse::<f64>().is_ok()
+    }
+
+    fn is_bracket(&self, bracket: &str) -> bool {
+        !self.quoted && self.text == bracket
+    }
+
+    // Parameters are declared as "type name"
+    fn is_declaration(&self) -> bool {
+        self.quoted && self.text.split_whitespace().count() == 2
+    }
+
+    fn value(&self) -> Value {
+        match self.text.parse::<f64>() {
+            Ok(number) if !self.quoted => Value::Number(number),
+            _ => Value::Text(self.text.clone())
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return Err((line, "unterminated string".to_string())),
+                        Some(c) => text.push(c)
+                    }
+                }
+                tokens.push(Token { text, quoted: true, line });
+            },
+            '[' | ']' => tokens.push(Token { text: c.to_string(), quoted: false, line }),
+            _ => {
+                let mut text = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !"[]\"#".contains(c)) {
+                    text.push(c);
+                }
+                tokens.push(Token { text, quoted: false, line });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    Text(String)
+}
+
+struct Parameter {
+    kind: String,
+    name: String,
+    values: Vec<Value>
+}
+
+// A directive with its arguments, such as the numbers of Translate or the
+// type of a Shape, and its parameter list
+struct Statement {
+    directive: String,
+    arguments: Vec<Value>,
+    parameters: Vec<Parameter>,
+    line: usize
+}
+
+impl Statement {
+    // Returns the arguments, which must be count numbers
+    fn arguments(&self, count: usize) -> Result<Vec<f64>, String> {
+        let numbers: Vec<f64> = self.arguments.iter().filter_map(|value| match value {
+            Value::Number(number) => Some(*number),
+            Value::Text(_) => None
+        }).collect();
+        if numbers.len() != count || self.arguments.len() != count {
+            return Err(format!("{} takes {} numbers", self.directive, count));
+        }
+        Ok(numbers)
+    }
+
+    // Returns the first argument, which must be a string
+    fn name(&self) -> Result<&str, String> {
+        match self.arguments.first() {
+            Some(Value::Text(text)) => Ok(text),
+            _ => Err(format!("{} needs a name", self.directive))
+        }
+    }
+
+    fn parameter(&self, name: &str) -> Option<&Parameter> {
+        self.parameters.iter().find(|parameter| parameter.name == name)
+    }
+
+    fn numbers(&self, name: &str) -> Result<Option<Vec<f64>>, String> {
+        let Some(parameter) = self.parameter(name) else { return Ok(None) };
+        parameter.values.iter().map(|value| match value {
+            Value::Number(number) => Ok(*number),
+            Value::Text(_) => Err(format!("parameter \"{} {}\" must be numeric", parameter.kind, parameter.name))
+        }).collect::<Result<Vec<f64>, String>>().map(Some)
+    }
+
+    fn number(&self, name: &str, default: f64) -> Result<f64, String> {
+        Ok(self.numbers(name)?.and_then(|numbers| numbers.first().copied()).unwrap_or(default))
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.parameter(name)?.values.first()? {
+            Value::Text(text) => Some(text),
+            Value::Number(_) => None
+        }
+    }
+}
+
+fn parse_statement(tokens: &[Token], i: &mut usize) -> Result<Statement, (usize, String)> {
+    let token = &tokens[*i];
+    if token.quoted || token.is_number() || token.is_bracket("[") || token.is_bracket("]") {
+        return Err((token.line, format!("expected a directive, found `{}`", token.text)));
+    }
+    let (directive, line) = (token.text.clone(), token.line);
+    *i += 1;
+
+    // Arguments go on until the first parameter or the next directive
+    let mut arguments = Vec::new();
+    while let Some(token) = tokens.get(*i) && !token.is_declaration() {
+        if token.is_bracket("[") {
+            arguments.extend(list(tokens, i)?);
+            continue;
+        }
+        if !token.quoted && !token.is_number() { break }
+        arguments.push(token.value());
+        *i += 1;
+    }
+
+    let mut parameters = Vec::new();
+    while let Some(declaration) = tokens.get(*i) && declaration.is_declaration() {
+        *i += 1;
+        let values = match tokens.get(*i) {
+            Some(token) if token.is_bracket("[") => list(tokens, i)?,
+            Some(token) if token.quoted || token.is_number() || token.text == "true" || token.text == "false" => {
+                *i += 1;
+                vec![token.value()]
+            },
+            _ => return Err((declaration.line, format!("parameter \"{}\" has no value", declaration.text)))
+        };
+
+        let mut words = declaration.text.split_whitespace();
+        let (kind, name) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+        parameters.push(Parameter { kind: kind.to_string(), name: name.to_string(), values });
+    }
+
+    Ok(Statement { directive, arguments, parameters, line })
+}
+
+// Reads the values between a pair of brackets, starting at the opening one
+fn list(tokens: &[Token], i: &mut usize) -> Result<Vec<Value>, (usize, String)> {
+    let line = tokens[*i].line;
+    *i += 1;
+    let mut values = Vec::new();
+    while let Some(token) = tokens.get(*i) {
+        *i += 1;
+        if token.is_bracket("]") { return Ok(values) }
+        if token.is_bracket("[") { return Err((token.line, "lists can't be nested".to_string())) }
+        values.push(token.value());
+    }
+    Err((line, "unclosed `[`".to_string()))
+}
+
+// The state that AttributeBegin saves and AttributeEnd restores
+#[derive(Clone)]
+struct GraphicsState {
+    transform: Transform,
+    material: Arc<dyn Material>,
+    // Radiance emitted by shapes, set by AreaLightSource
+    emission: Option<Vector>
+}
+
+struct PbrtCamera {
+    position: Vector,
+    forward: Vector,
+    up: Vector,
+    right: Vector,
+    fov: f64,
+    lens_radius: f64,
+    focal_distance: f64
+}
+
+impl PbrtCamera {
+    // pbrt cameras look down their +Z axis, with +Y pointing up and +X to the
+    // right of the image
+    fn new(world_from_camera: &Transform, fov: f64, lens_radius: f64, focal_distance: f64) -> Self {
+        Self {
+            position: world_from_camera.point(&Vector::ZERO),
+            forward: world_from_camera.vector(&Vector::new(0., 0., 1.)).normalize(),
+            up: world_from_camera.vector(&Vector::new(0., 1., 0.)),
+            right: world_from_camera.vector(&Vector::new(1., 0., 0.)),
+            fov,
+            lens_radius,
+            focal_distance
+        }
+    }
+
+    // caracol places forward x up to the right of the image. When pbrt places
+    // the opposite direction there, as LookAt does, mirroring the world across
+    // the plane of forward and up keeps images the same
+    fn mirror(&self) -> Transform {
+        if self.right.dot(&self.forward.cross(&self.up)) < 0. {
+            reflection(self.position, self.right.normalize())
+        } else {
+            Transform::IDENTITY
+        }
+    }
+}
+
+impl Default for PbrtCamera {
+    fn default() -> Self {
+        Self::new(&Transform::IDENTITY, 90f64.to_radians(), 0., 1e6)
+    }
+}
+
+struct Builder {
+    // Relative paths are resolved against the directory of the main file,
+    // even in included files
+    directory: PathBuf,
+    render: RenderSettings,
+    camera: Option<PbrtCamera>,
+    // Applied to everything in the world to make it look like in pbrt. See
+    // set_camera
+    mirror: Transform,
+    graphics: GraphicsState,
+    // Saved states, and whether they were saved by TransformBegin, which only
+    // restores the transform
+    stack: Vec<(GraphicsState, bool)>,
+    named_materials: HashMap<String, Arc<dyn Material>>,
+    // Objects without shapes are None
+    objects: HashMap<String, Option<Arc<dyn SceneElement>>>,
+    // The name and shapes of the object between ObjectBegin and ObjectEnd
+    object: Option<(String, Vec<Arc<dyn SceneElement>>)>,
+    elements: Vec<Arc<dyn SceneElement>>,
+    warned: HashSet<String>,
+    // Canonical paths of the files being read, the main file first, to catch
+    // files that include themselves
+    including: Vec<PathBuf>
+}
+
+impl Builder {
+    fn new(directory: &Path) -> Self {
+        // pbrt's defaults, which differ from caracol's
+        let render = RenderSettings {
+            width: 1280,
+            height: 720,
+            rays_per_pixel: 16,
+            bounces: 5,
+            void: Vector::ZERO,
+            seed: None
+        };
+        let material: Arc<dyn Material> = Arc::new(TwoSidedMaterial::new(Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.))));
+
+        Self {
+            directory: directory.to_path_buf(),
+            render,
+            camera: None,
+            mirror: Transform::IDENTITY,
+            graphics: GraphicsState { transform: Transform::IDENTITY, material, emission: None },
+            stack: Vec::new(),
+            named_materials: HashMap::new(),
+            objects: HashMap::new(),
+            object: None,
+            elements: Vec::new(),
+            warned: HashSet::new(),
+            including: Vec::new()
+        }
+    }
+
+    fn include(&mut self, path: &Path) -> Result<(), PbrtError> {
+        let io_error = |error| PbrtError::Io { path: path.to_path_buf(), error };
+        let source = fs::read_to_string(path).map_err(io_error)?;
+        self.including.push(path.canonicalize().map_err(io_error)?);
+        let error = |(line, message): (usize, String)| PbrtError::Parse { path: path.to_path_buf(), line, message };
+
+        let tokens = tokenize(&source).map_err(error)?;
+        let mut i = 0;
+        while i < tokens.len() {
+            let statement = parse_statement(&tokens, &mut i).map_err(error)?;
+            if statement.directive == "Include" || statement.directive == "Import" {
+                let file = statement.name().map_err(|message| error((statement.line, message)))?;
+                let included = self.directory.join(file);
+                if included.canonicalize().is_ok_and(|included| self.including.contains(&included)) {
+                    return Err(error((statement.line, format!("{} includes itself", file))));
+                }
+                self.include(&included)?;
+                continue;
+            }
+            self.statement(&statement, path).map_err(|message| error((statement.line, message)))?;
+        }
+        self.including.pop();
+        Ok(())
+    }
+
+    fn warn(&mut self, path: &Path, line: usize, message: String) {
+        if self.warned.insert(message.clone()) {
+            eprintln!("{}:{}: {}", path.display(), line, message);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement, path: &Path) -> Result<(), String> {
+        let transform = self.graphics.transform;
+        match statement.directive.as_str() {
+            "LookAt" => {
+                let v = statement.arguments(9)?;
+                let camera_from_world = look_at(
+                    Vector::new(v[0], v[1], v[2]),
+                    Vector::new(v[3], v[4], v[5]),
+                    Vector::new(v[6], v[7], v[8])
+                ).ok_or("LookAt needs an up vector that isn't parallel to the view direction")?;
+                self.graphics.transform = transform * camera_from_world;
+            },
+            "Translate" => {
+                let v = statement.arguments(3)?;
+                self.graphics.transform = transform * Transform::translation(Vector::new(v[0], v[1], v[2]));
+            },
+            "Scale" => {
+                let v = statement.arguments(3)?;
+                if v.contains(&0.) { return Err("can't scale by 0".to_string()) }
+                self.graphics.transform = transform * Transform::scaling(Vector::new(v[0], v[1], v[2]));
+            },
+            "Rotate" => {
+                let v = statement.arguments(4)?;
+                let axis = Vector::new(v[1], v[2], v[3]);
+                if axis.is_zero() { return Err("can't rotate around a zero axis".to_string()) }
+                self.graphics.transform = transform * Transform::rotation(axis.normalize(), v[0].to_radians());
+            },
+            "Identity" => self.graphics.transform = Transform::IDENTITY,
+            "Transform" | "ConcatTransform" => {
+                let v = statement.arguments(16)?;
+                // The matrix is given column by column
+                let mut matrix = [[0.; 4]; 4];
+                for (i, row) in matrix.iter_mut().enumerate() {
+                    for (j, value) in row.iter_mut().enumerate() {
+                        *value = v[j * 4 + i];
+                    }
+                }
+                let given = Transform::new(matrix).ok_or("the matrix can't be inverted")?;
+                self.graphics.transform = if statement.directive == "Transform" { given } else { transform * given };
+            },
+            "Camera" => {
+                let kind = statement.name()?;
+                if kind != "perspective" {
+                    self.warn(path, statement.line, format!("treating the {} camera as a perspective one", kind));
+                }
+                self.set_camera(PbrtCamera::new(
+                    &transform.inverse(),
+                    statement.number("fov", 90.)?.to_radians(),
+                    statement.number("lensradius", 0.)?,
+                    statement.number("focaldistance", 1e6)?
+                ));
+            },
+            "Film" => {
+                let width = statement.number("xresolution", self.render.width as f64)?;
+                let height = statement.number("yresolution", self.render.height as f64)?;
+                if width < 1. || height < 1. { return Err("the film resolution must be positive".to_string()) }
+                self.render.width = width as u32;
+                self.render.height = height as u32;
+            },
+            "Sampler" => self.render.rays_per_pixel = statement.number("pixelsamples", 16.)?.max(1.) as u32,
+            "Integrator" => self.render.bounces = statement.number("maxdepth", 5.)?.clamp(0., u8::MAX as f64) as u8,
+            "WorldBegin" => {
+                if self.camera.is_none() {
+                    self.set_camera(PbrtCamera::default());
+                }
+                self.graphics.transform = Transform::IDENTITY;
+            },
+            "AttributeBegin" | "TransformBegin" => {
+                self.stack.push((self.graphics.clone(), statement.directive == "TransformBegin"));
+            },
+            "AttributeEnd" | "TransformEnd" => {
+                let (saved, transform_only) = self.stack.pop()
+                    .ok_or_else(|| format!("{} without a matching begin", statement.directive))?;
+                if transform_only {
+                    self.graphics.transform = saved.transform;
+                } else {
+                    self.graphics = saved;
+                }
+            },
+            "Material" => {
+                let material = self.material(statement.name()?, statement, path)?;
+                self.graphics.material = material;
+            },
+            "MakeNamedMaterial" => {
+                let kind = statement.string("type").ok_or("named materials need a \"string type\"")?;
+                let material = self.material(kind, statement, path)?;
+                self.named_materials.insert(statement.name()?.to_string(), material);
+            },
+            "NamedMaterial" => {
+                let name = statement.name()?;
+                self.graphics.material = self.named_materials.get(name).cloned()
+                    .ok_or_else(|| format!("unknown material `{}`", name))?;
+            },
+            "AreaLightSource" => {
+                let kind = statement.name()?;
+                if kind != "diffuse" {
+                    self.warn(path, statement.line, format!("ignoring unsupported area light `{}`", kind));
+                    return Ok(());
+                }
+                let radiance = self.rgb(statement, "L", path)?.unwrap_or(Vector::uniform(1.));
+                self.graphics.emission = Some(radiance * statement.number("scale", 1.)?);
+            },
+            "Shape" => self.shape(statement, path)?,
+            "ObjectBegin" => {
+                if self.object.is_some() { return Err("objects can't be nested".to_string()) }
+                self.object = Some((statement.name()?.to_string(), Vec::new()));
+                self.stack.push((self.graphics.clone(), false));
+            },
+            "ObjectEnd" => {
+                let (name, elements) = self.object.take().ok_or("ObjectEnd without a matching ObjectBegin")?;
+                let object = (!elements.is_empty()).then(|| build_bvh(elements));
+                self.objects.insert(name, object);
+                if let Some((saved, _)) = self.stack.pop() {
+                    self.graphics = saved;
+                }
+            },
+            "ObjectInstance" => {
+                let name = statement.name()?;
+                let object = self.objects.get(name).ok_or_else(|| format!("unknown object `{}`", name))?;
+                if let Some(object) = object {
+                    self.elements.push(Arc::new(Instance::new(object.clone(), self.mirror * transform)));
+                }
+            },
+            // These don't change what the scene looks like
+            "WorldEnd" | "Option" | "ColorSpace" | "PixelFilter" | "Accelerator" => (),
+            directive => self.warn(path, statement.line, format!("ignoring unsupported directive `{}`", directive))
+        }
+        Ok(())
+    }
+
+    fn set_camera(&mut self, camera: PbrtCamera) {
+        self.mirror = camera.mirror();
+        self.camera = Some(camera);
+    }
+
+    // Returns the "rgb" parameter name. Other kinds of colors, like spectra
+    // and textures, are ignored with a warning
+    fn rgb(&mut self, statement: &Statement, name: &str, path: &Path) -> Result<Option<Vector>, String> {
+        let Some(parameter) = statement.parameter(name) else { return Ok(None) };
+        if parameter.kind != "rgb" {
+            self.warn(path, statement.line, format!("ignoring \"{} {}\", only rgb colors are supported", parameter.kind, name));
+            return Ok(None);
+        }
+        match statement.numbers(name)?.as_deref() {
+            Some(&[r, g, b]) => Ok(Some(Vector::new(r, g, b))),
+            _ => Err(format!("\"rgb {}\" needs three values", name))
+        }
+    }
+
+    fn material(&mut self, kind: &str, statement: &Statement, path: &Path) -> Result<Arc<dyn Material>, String> {
+        let material: Arc<dyn Material> = match kind {
+            "diffuse" => {
+                let reflectance = self.rgb(statement, "reflectance", path)?.unwrap_or(Vector::uniform(0.5));
+                Arc::new(DiffuseMaterial::new(reflectance, 0.))
+            },
+            "conductor" => {
+                let color = match self.rgb(statement, "reflectance", path)? {
+                    Some(color) => color,
+                    None => metal_color(statement.string("eta").unwrap_or("metal-Cu-eta"))
+                };
+                let roughness = match statement.numbers("roughness")? {
+                    Some(_) => statement.number("roughness", 0.)?,
+                    None => (statement.number("uroughness", 0.)? + statement.number("vroughness", 0.)?) / 2.
+                };
+                Arc::new(ReflectiveMaterial::new(color, 0., 1. - roughness.clamp(0., 1.)))
+            },
+            "dielectric" | "thindielectric" => {
+                // Spectra, used for real glasses, are replaced by a typical index
+                let eta = match statement.parameter("eta") {
+                    Some(parameter) if parameter.kind == "float" => statement.number("eta", 1.5)?,
+                    _ => 1.5
+                };
+                // Dielectrics already handle both sides of surfaces
+                return Ok(Arc::new(DielectricMaterial::new(Vector::uniform(1.), eta)));
+            },
+            _ => {
+                self.warn(path, statement.line, format!("replacing unsupported material `{}` with a diffuse one", kind));
+                Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.))
+            }
+        };
+        Ok(Arc::new(TwoSidedMaterial::new(material)))
+    }
+
+    fn shape(&mut self, statement: &Statement, path: &Path) -> Result<(), String> {
+        // Area lights replace the material of their shapes
+        let material = match self.graphics.emission {
+            Some(radiance) => {
+                let intensity = radiance.x.max(radiance.y).max(radiance.z);
+                let color = if intensity > 0. { radiance / intensity } else { Vector::ZERO };
+                Arc::new(TwoSidedMaterial::new(Arc::new(DiffuseMaterial::new(color, intensity))))
+            },
+            None => self.graphics.material.clone()
+        };
+        // Shapes of objects are only mirrored through their instances
+        let transform = match self.object {
+            Some(_) => self.graphics.transform,
+            None => self.mirror * self.graphics.transform
+        };
+
+        let element: Arc<dyn SceneElement> = match statement.name()? {
+            "sphere" => {
+                let sphere = Sphere::new(Vector::ZERO, statement.number("radius", 1.)?, material);
+                Arc::new(Transformed::new(Arc::new(sphere), transform))
+            },
+            "trianglemesh" => Arc::new(triangle_mesh(statement, material, &transform)?),
+            "plymesh" => {
+                let filename = statement.string("filename").ok_or("ply meshes need a \"string filename\"")?;
+                let filename = self.directory.join(filename);
+                Arc::new(read_ply_transformed(&filename.to_string_lossy(), material, &transform).map_err(|error| error.to_string())?)
+            },
+            kind => {
+                self.warn(path, statement.line, format!("ignoring unsupported shape `{}`", kind));
+                return Ok(());
+            }
+        };
+
+        match &mut self.object {
+            Some((_, elements)) => elements.push(element),
+            None => self.elements.push(element)
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Scene {
+        let camera = self.camera.unwrap_or_default();
+
+        // pbrt's field of view spans the shorter side of the image, and
+        // caracol's the horizontal one
+        let aspect_ratio = self.render.aspect_ratio();
+        let fov_angle = if aspect_ratio > 1. {
+            2. * ((camera.fov / 2.).tan() * aspect_ratio).atan()
+        } else {
+            camera.fov
+        };
+        let (look_at, defocus_angle) = if camera.lens_radius > 0. {
+            (camera.position + camera.forward * camera.focal_distance, (camera.lens_radius / camera.focal_distance).min(1.).asin())
+        } else {
+            (camera.position + camera.forward, 0.)
+        };
+
+        Scene {
+            render: self.render,
+            camera: CameraSettings {
+                position: camera.position,
+                look_at,
+                view_up: camera.up,
+                fov_angle,
+                defocus_angle
+            },
+            elements: self.elements
+        }
+    }
+}
+
+fn triangle_mesh(statement: &Statement, material: Arc<dyn Material>, transform: &Transform) -> Result<Mesh, String> {
+    let points = statement.numbers("P")?.ok_or("triangle meshes need \"point3 P\"")?;
+    if !points.len().is_multiple_of(3) { return Err("\"point3 P\" needs three values per point".to_string()) }
+    let vertices: Vec<Vector> = points.chunks_exact(3)
+        .map(|p| transform.point(&Vector::new(p[0], p[1], p[2])))
+        .collect();
+    let count = vertices.len();
+
+    let indices: Vec<u32> = match statement.numbers("indices")? {
+        Some(indices) => indices.into_iter().map(|index| {
+            if index >= 0. && index < count as f64 && index.fract() == 0. {
+                Ok(index as u32)
+            } else {
+                Err(format!("index {} is out of range, the mesh has {} vertices", index, count))
+            }
+        }).collect::<Result<_, _>>()?,
+        None if count == 3 => vec![0, 1, 2],
+        None => return Err("triangle meshes with more than three vertices need \"integer indices\"".to_string())
+    };
+    if !indices.len().is_multiple_of(3) { return Err("\"integer indices\" needs three values per triangle".to_string()) }
+
+    let normals: Vec<Vector> = statement.numbers("N")?.unwrap_or_default().chunks_exact(3)
+        .map(|n| transform.normal(&Vector::new(n[0], n[1], n[2])).normalize())
+        .collect();
+    let uvs: Vec<(f64, f64)> = statement.numbers("uv")?.unwrap_or_default().chunks_exact(2)
+        .map(|uv| (uv[0], uv[1]))
+        .collect();
+    let (has_normals, has_uvs) = (normals.len() == count, uvs.len() == count);
+
+    let flip = transform.flips_orientation();
+    let faces = indices.chunks_exact(3).map(|triangle| {
+        let mut vertices = [triangle[0], triangle[1], triangle[2]];
+        if flip { vertices.swap(1, 2); }
+        Face {
+            vertices,
+            normals: has_normals.then_some(vertices),
+            uvs: has_uvs.then_some(vertices),
+            material: 0
+        }
+    }).collect();
+
+    let buffers = MeshBuffers {
+        vertices,
+        normals: if has_normals { normals } else { Vec::new() },
+        uvs: if has_uvs { uvs } else { Vec::new() },
+        colors: Vec::new()
+    };
+    Ok(Mesh::new(Arc::new(buffers), faces, vec![material]))
+}
+
+// pbrt's LookAt, which returns the transform from world space to a camera at
+// position looking at target
+fn look_at(position: Vector, target: Vector, up: Vector) -> Option<Transform> {
+    let direction = (target - position).normalize();
+    let right = up.normalize().cross(&direction);
+    if right.is_zero() { return None }
+    let right = right.normalize();
+    let up = direction.cross(&right);
+
+    let world_from_camera = [
+        [right.x, up.x, direction.x, position.x],
+        [right.y, up.y, direction.y, position.y],
+        [right.z, up.z, direction.z, position.z],
+        [0., 0., 0., 1.]
+    ];
+    Some(Transform::new(world_from_camera)?.inverse())
+}
+
+// Mirrors space across the plane through point with the given unit normal
+fn reflection(point: Vector, normal: Vector) -> Transform {
+    let n = [normal.x, normal.y, normal.z];
+    let offset = 2. * normal.dot(&point);
+    let mut matrix = [[0., 0., 0., 1.]; 4];
+    for (i, row) in matrix.iter_mut().take(3).enumerate() {
+        for (j, value) in row.iter_mut().take(3).enumerate() {
+            *value = if i == j { 1. } else { 0. } - 2. * n[i] * n[j];
+        }
+        row[3] = offset * n[i];
+    }
+    Transform::new(matrix).unwrap_or(Transform::IDENTITY)
+}
+
+// Approximate colors of the metals pbrt has spectra for
+fn metal_color(eta: &str) -> Vector {
+    match eta {
+        "metal-Ag-eta" => Vector::new(0.97, 0.96, 0.92),
+        "metal-Al-eta" => Vector::new(0.91, 0.92, 0.92),
+        "metal-Au-eta" => Vector::new(1., 0.77, 0.34),
+        "metal-Cu-eta" => Vector::new(0.96, 0.64, 0.54),
+        _ => Vector::uniform(0.9)
+    }
+}
@@ -0,0 +1,261 @@
+use crate::config::{BvhSettings, MIN_OVERLAP, PARALLEL_BUILD_SIZE, SPATIAL_BINS};
+use crate::geometry::hitbox::HitBox;
+use crate::geometry::vector::Vector;
+use crate::scene::bvh::{bounds, component, keep_leaf, merge_bounds, BvhNode, ObjectSplit};
+use crate::utils::utils::Axis;
+
+// An item, and the bounds of the part of it that the reference covers
+type Reference = (u32, HitBox);
+
+// Builds a BVH where items that straddle a split can be split in two when that
+// is cheaper, and returns its nodes and the indices of the items in its
+// leaves. Spatial splits add at most max_split_growth times as many references
+// as there are items
+// https://www.nvidia.com/docs/IO/77714/sbvh.pdf
+pub fn build_spatial(
+    hitboxes: &[HitBox],
+    settings: &BvhSettings,
+    clip: &(impl Fn(usize, &HitBox) -> Option<HitBox> + Sync)
+) -> (Vec<BvhNode>, Vec<u32>) {
+    let references: Vec<Reference> = hitboxes.iter().enumerate().map(|(i, hitbox)| (i as u32, *hitbox)).collect();
+    let budget = (hitboxes.len() as f64 * settings.max_split_growth) as usize;
+    let root_area = bounds(&references).area();
+    build(references, budget, root_area, settings, clip)
+}
+
+// Builds a subtree, with the children of its inner nodes and the items of its
+// leaves relative to the start of its own lists. budget is how many references
+// it can add
+fn build(
+    mut references: Vec<Reference>,
+    budget: usize,
+    root_area: f64,
+    settings: &BvhSettings,
+    clip: &(impl Fn(usize, &HitBox) -> Option<HitBox> + Sync)
+) -> (Vec<BvhNode>, Vec<u32>) {
+    let n = references.len();
+    let hitbox = bounds(&references);
+    let leaf = |references: Vec<Reference>| {
+        (vec![BvhNode::leaf(&hitbox, 0, n)], references.into_iter().map(|(i, _)| i).collect())
+    };
+    if n == 1 { return leaf(references) }
+
+    let object = ObjectSplit::find(&references, |reference| &reference.1);
+    let overlap = object.as_ref().map_or(f64::INFINITY, |split| {
+        split.left.intersection(&split.right).map_or(0., |overlap| overlap.area())
+    });
+    let spatial = if budget > 0 && overlap > MIN_OVERLAP * root_area {
+        SpatialSplit::find(&references, &hitbox, budget, clip)
+            .filter(|split| object.as_ref().is_none_or(|object| split.cost < object.cost))
+    } else {
+        None
+    };
+
+    let cost = spatial.as_ref().map(|split| split.cost).or(object.as_ref().map(|split| split.cost));
+    if keep_leaf(n, &hitbox, cost, settings) { return leaf(references) }
+
+    let (left, right) = match (spatial, object) {
+        (Some(split), _) => split.apply(references, clip),
+        (None, Some(split)) => {
+            let count = split.partition(&mut references, |reference| &reference.1);
+            let right = references.split_off(count);
+            (references, right)
+        },
+        (None, None) => {
+            let right = references.split_off(n / 2);
+            (references, right)
+        }
+    };
+
+    // The budget left is shared in proportion to the size of each side
+    let budget = budget.saturating_sub(left.len() + right.len() - n);
+    let left_budget = budget * left.len() / (left.len() + right.len());
+    let right_budget = budget - left_budget;
+    let ((left_nodes, left_indices), (right_nodes, right_indices)) = if n >= PARALLEL_BUILD_SIZE {
+        rayon::join(
+            || build(left, left_budget, root_area, settings, clip),
+            || build(right, right_budget, root_area, settings, clip)
+        )
+    } else {
+        (build(left, left_budget, root_area, settings, clip), build(right, right_budget, root_area, settings, clip))
+    };
+
+    let right = 1 + left_nodes.len();
+    let mut nodes = Vec::with_capacity(right + right_nodes.len());
+    nodes.push(BvhNode::inner(&hitbox, right, &left_nodes[0].hitbox(), &right_nodes[0].hitbox()));
+    nodes.extend(left_nodes.into_iter().map(|node| node.offset(1, 0)));
+    nodes.extend(right_nodes.into_iter().map(|node| node.offset(right, left_indices.len())));
+
+    let mut indices = left_indices;
+    indices.extend(right_indices);
+    (nodes, indices)
+}
+
+// A split along a plane, where references that straddle it are split in two
+struct SpatialSplit {
+    axis: Axis,
+    position: f64,
+    // The area of each side times its number of references, added up
+    cost: f64
+}
+
+impl SpatialSplit {
+    // Tries the planes between SPATIAL_BINS bins along each axis of hitbox
+    // that add at most budget references
+    fn find(references: &[Reference], hitbox: &HitBox, budget: usize, clip: &impl Fn(usize, &HitBox) -> Option<HitBox>) -> Option<Self> {
+        let n = references.len();
+        let mut best: Option<Self> = None;
+
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            let low = component(&hitbox.start(), axis);
+            let width = (component(&hitbox.end(), axis) - low) / SPATIAL_BINS as f64;
+            if width <= 0. { continue }
+            let bin = |value: f64| (((value - low) / width) as usize).min(SPATIAL_BINS - 1);
+
+            // References are counted where they start and where they end, and
+            // the bounds of each bin hold the parts of them inside it
+            let mut bins: [Option<HitBox>; SPATIAL_BINS] = [None; SPATIAL_BINS];
+            let mut entries = [0usize; SPATIAL_BINS];
+            let mut exits = [0; SPATIAL_BINS];
+            for (item, bounds) in references {
+                let (first, last) = (bin(component(&bounds.start(), axis)), bin(component(&bounds.end(), axis)));
+                entries[first] += 1;
+                exits[last] += 1;
+                if first == last {
+                    merge_bounds(&mut bins[first], &Some(*bounds));
+                    continue;
+                }
+
+                for (i, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let from = low + i as f64 * width;
+                    let part = slab(bounds, axis, from, from + width).and_then(|slab| clip(*item as usize, &slab));
+                    merge_bounds(bin, &part);
+                }
+            }
+
+            let mut rights: [Option<HitBox>; SPATIAL_BINS] = [None; SPATIAL_BINS];
+            let mut right_counts = [0; SPATIAL_BINS];
+            let mut right: Option<HitBox> = None;
+            let mut count = 0;
+            for i in (1..SPATIAL_BINS).rev() {
+                merge_bounds(&mut right, &bins[i]);
+                count += exits[i];
+                rights[i - 1] = right;
+                right_counts[i - 1] = count;
+            }
+
+            let mut left: Option<HitBox> = None;
+            let mut count = 0;
+            for i in 0..SPATIAL_BINS - 1 {
+                merge_bounds(&mut left, &bins[i]);
+                count += entries[i];
+                let (Some(left), Some(right)) = (left, rights[i]) else { continue };
+                if count == 0 || right_counts[i] == 0 { continue }
+
+                let duplicates = (count + right_counts[i]).saturating_sub(n);
+                if duplicates > budget { continue }
+
+                let cost = left.area() * count as f64 + right.area() * right_counts[i] as f64;
+                if best.as_ref().is_none_or(|best| cost < best.cost) {
+                    let position = low + (i + 1) as f64 * width;
+                    best = Some(Self { axis, position, cost });
+                }
+            }
+        }
+        best
+    }
+
+    // Splits references between both sides, clipping the ones that straddle
+    // the plane
+    fn apply(&self, references: Vec<Reference>, clip: &impl Fn(usize, &HitBox) -> Option<HitBox>) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for (item, bounds) in references {
+            if component(&bounds.end(), self.axis) <= self.position {
+                left.push((item, bounds));
+            } else if component(&bounds.start(), self.axis) >= self.position {
+                right.push((item, bounds));
+            } else {
+                let below = slab(&bounds, self.axis, f64::NEG_INFINITY, self.position).and_then(|slab| clip(item as usize, &slab));
+                let above = slab(&bounds, self.axis, self.position, f64::INFINITY).and_then(|slab| clip(item as usize, &slab));
+                match (below, above) {
+                    (None, None) => left.push((item, bounds)),
+                    (below, above) => {
+                        left.extend(below.map(|bounds| (item, bounds)));
+                        right.extend(above.map(|bounds| (item, bounds)));
+                    }
+                }
+            }
+        }
+
+        // Clipping can leave a side empty, then the split is undone
+        if left.is_empty() || right.is_empty() {
+            let n = left.len() + right.len();
+            let mut references = left;
+            references.extend(right);
+            let right = references.split_off(n / 2);
+            return (references, right);
+        }
+        (left, right)
+    }
+}
+
+// Returns the bounds of the part of a triangle inside bounds, if there is any
+// https://en.wikipedia.org/wiki/Sutherland%E2%80%93Hodgman_algorithm
+pub fn clip_triangle(vertices: [Vector; 3], bounds: &HitBox) -> Option<HitBox> {
+    // Each of the 6 planes adds at most one point to the polygon
+    let mut polygon = [Vector::new(0., 0., 0.); 9];
+    polygon[..3].copy_from_slice(&vertices);
+    let mut len = 3;
+
+    for axis in [Axis::X, Axis::Y, Axis::Z] {
+        for (limit, below) in [(component(&bounds.start(), axis), false), (component(&bounds.end(), axis), true)] {
+            let inside = |point: &Vector| {
+                let value = component(point, axis);
+                if below { value <= limit } else { value >= limit }
+            };
+            if polygon[..len].iter().all(inside) { continue }
+
+            let mut clipped = [Vector::new(0., 0., 0.); 9];
+            let mut clipped_len = 0;
+            for i in 0..len {
+                let (a, b) = (&polygon[i], &polygon[(i + 1) % len]);
+                if inside(a) {
+                    clipped[clipped_len] = *a;
+                    clipped_len += 1;
+                }
+                if inside(a) != inside(b) {
+                    let t = (limit - component(a, axis)) / (component(b, axis) - component(a, axis));
+                    clipped[clipped_len] = with_component(a + (b - a) * t, axis, limit);
+                    clipped_len += 1;
+                }
+            }
+
+            (polygon, len) = (clipped, clipped_len);
+            if len == 0 { return None }
+        }
+    }
+
+    let mut clipped = HitBox::new(polygon[0], polygon[0]);
+    for point in &polygon[1..len] {
+        clipped.merge(&HitBox::new(*point, *point));
+    }
+    // Rounding can put points slightly outside
+    clipped.intersection(bounds)
+}
+
+// The part of bounds between from and to along axis
+fn slab(bounds: &HitBox, axis: Axis, from: f64, to: f64) -> Option<HitBox> {
+    let start = component(&bounds.start(), axis).max(from);
+    let end = component(&bounds.end(), axis).min(to);
+    (start <= end).then(|| HitBox::new(with_component(bounds.start(), axis, start), with_component(bounds.end(), axis, end)))
+}
+
+fn with_component(vector: Vector, axis: Axis, value: f64) -> Vector {
+    match axis {
+        Axis::X => Vector::new(value, vector.y, vector.z),
+        Axis::Y => Vector::new(vector.x, value, vector.z),
+        Axis::Z => Vector::new(vector.x, vector.y, value)
+    }
+}
+
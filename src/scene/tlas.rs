@@ -0,0 +1,227 @@
+use std::sync::Arc;
+use rayon::prelude::*;
+use crate::config::BvhSettings;
+use crate::geometry::hitbox::HitBox;
+use crate::geometry::ray::Ray;
+use crate::geometry::transform::Transform;
+use crate::geometry::vector::Vector;
+use crate::scene::bvh::{BvhStats, BvhTree};
+use crate::scene::elements::{CollisionInfo, ElementVisitor, Placement, SceneElement, Transformed};
+
+// Refers to an object of a Tlas. Ids of removed objects are never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectId(usize);
+
+struct Object {
+    // The element with its own BVH, like a Mesh or a BVH built with build_bvh
+    blas: Arc<dyn SceneElement>,
+    transform: Transform,
+    // blas placed with transform, or blas itself if the transform is the
+    // identity
+    placed: Arc<dyn SceneElement>
+}
+
+impl Object {
+    fn new(blas: Arc<dyn SceneElement>, transform: Transform) -> Self {
+        let placed = if transform.matrix() == Transform::IDENTITY.matrix() {
+            blas.clone()
+        } else {
+            Arc::new(Transformed::new(blas.clone(), transform))
+        };
+        Self { blas, transform, placed }
+    }
+}
+
+// A two-level acceleration structure: a top-level BVH over objects that each
+// have a bottom-level BVH of their own. Adding, removing or moving an object
+// only rebuilds the top level, which is cheap since it has one item per object
+pub struct Tlas {
+    objects: Vec<Option<Object>>,
+    settings: BvhSettings,
+    tree: BvhTree,
+    // The object of each item of tree
+    items: Vec<usize>,
+    hitbox: HitBox
+}
+
+impl Tlas {
+    // Places every element as an object where it is
+    pub fn new(elements: Vec<Arc<dyn SceneElement>>) -> Self {
+        Self::with_settings(elements, &BvhSettings::default())
+    }
+
+    // Like new, but builds the top level with the given cost model
+    pub fn with_settings(elements: Vec<Arc<dyn SceneElement>>, settings: &BvhSettings) -> Self {
+        let mut tlas = Self {
+            objects: elements.into_iter().map(|blas| Some(Object::new(blas, Transform::IDENTITY))).collect(),
+            settings: *settings,
+            tree: BvhTree::default(),
+            items: Vec::new(),
+            hitbox: HitBox::new(Vector::ZERO, Vector::ZERO)
+        };
+        tlas.rebuild();
+        tlas
+    }
+
+    // Adds an object placed with transform and returns its id
+    pub fn add(&mut self, blas: Arc<dyn SceneElement>, transform: Transform) -> ObjectId {
+        self.objects.push(Some(Object::new(blas, transform)));
+        self.rebuild();
+        ObjectId(self.objects.len() - 1)
+    }
+
+    // Removes an object and returns its element, or None if there is no such
+    // object
+    pub fn remove(&mut self, id: ObjectId) -> Option<Arc<dyn SceneElement>> {
+        let object = self.objects.get_mut(id.0)?.take()?;
+        self.rebuild();
+        Some(object.blas)
+    }
+
+    // Moves an object and returns its previous transform, or None if there is
+    // no such object
+    pub fn set_transform(&mut self, id: ObjectId, transform: Transform) -> Option<Transform> {
+        let object = self.objects.get_mut(id.0)?.as_mut()?;
+        let previous = object.transform;
+        *object = Object::new(object.blas.clone(), transform);
+        self.rebuild();
+        Some(previous)
+    }
+
+    // Replaces the element of an object, for example with the next frame of a
+    // deformed mesh, and returns the previous one, or None if there is no such
+    // object
+    pub fn replace(&mut self, id: ObjectId, blas: Arc<dyn SceneElement>) -> Option<Arc<dyn SceneElement>> {
+        let object = self.objects.get_mut(id.0)?.as_mut()?;
+        let previous = std::mem::replace(object, Object::new(blas, object.transform));
+        self.rebuild();
+        Some(previous.blas)
+    }
+
+    pub fn object(&self, id: ObjectId) -> Option<&Arc<dyn SceneElement>> {
+        self.objects.get(id.0)?.as_ref().map(|object| &object.blas)
+    }
+
+    pub fn transform(&self, id: ObjectId) -> Option<Transform> {
+        self.objects.get(id.0)?.as_ref().map(|object| object.transform)
+    }
+
+    // The ids of the objects, in the order they were added
+    pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        self.objects.iter().enumerate().filter(|(_, object)| object.is_some()).map(|(i, _)| ObjectId(i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // The closest hit of the ray before max_distance. Distances are measured
+    // in lengths of the ray's direction
+    pub fn closest_hit(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        self.collide(ray, max_distance)
+    }
+
+    // Whether the ray hits anything before max_distance. It stops at the first
+    // hit it finds, so it is cheaper than closest_hit. A ray from a point
+    // towards another whose direction is the difference between them is
+    // blocked between them if it is occluded before 1
+    pub fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.any_hit(ray, max_distance)
+    }
+
+    // Every hit of the ray before max_distance, nearest first
+    pub fn all_hits(&self, ray: &Ray, max_distance: f64) -> impl Iterator<Item = CollisionInfo> + use<> {
+        let mut hits = Vec::new();
+        self.collect_hits(ray, max_distance, &mut hits);
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits.into_iter()
+    }
+
+    // closest_hit for each ray, in parallel
+    pub fn closest_hit_batch(&self, rays: &[Ray], max_distance: f64) -> Vec<Option<CollisionInfo>> {
+        rays.par_iter().map(|ray| self.closest_hit(ray, max_distance)).collect()
+    }
+
+    // occluded for each ray, in parallel
+    pub fn occluded_batch(&self, rays: &[Ray], max_distance: f64) -> Vec<bool> {
+        rays.par_iter().map(|ray| self.occluded(ray, max_distance)).collect()
+    }
+
+    // all_hits for each ray, in parallel
+    pub fn all_hits_batch(&self, rays: &[Ray], max_distance: f64) -> Vec<Vec<CollisionInfo>> {
+        rays.par_iter().map(|ray| self.all_hits(ray, max_distance).collect()).collect()
+    }
+
+    // Builds the top level again over the current objects. Their own BVHs are
+    // left as they are
+    fn rebuild(&mut self) {
+        let (items, hitboxes): (Vec<usize>, Vec<HitBox>) = self.objects.iter().enumerate()
+            .filter_map(|(i, object)| Some((i, *object.as_ref()?.placed.hitbox())))
+            .unzip();
+        self.tree = BvhTree::build(&hitboxes, &self.settings);
+        self.items = items;
+        self.hitbox = self.tree.hitbox();
+    }
+
+    fn placed(&self, item: usize) -> &Arc<dyn SceneElement> {
+        let object = self.objects[self.items[item]].as_ref();
+        &object.expect("the top level only refers to objects that exist").placed
+    }
+}
+
+impl SceneElement for Tlas {
+    fn collide(&self, ray: &Ray, max_distance: f64) -> Option<CollisionInfo> {
+        let mut closest: Option<CollisionInfo> = None;
+        self.tree.traverse(ray, max_distance, |i, distance| {
+            let info = self.placed(i).collide(ray, distance)?;
+            let distance = info.distance;
+            closest = Some(info);
+            Some(distance)
+        });
+        closest
+    }
+
+    fn hitbox(&self) -> &HitBox {
+        &self.hitbox
+    }
+
+    fn any_hit(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.tree.any_hit(ray, max_distance, |i, _| self.placed(i).any_hit(ray, max_distance))
+    }
+
+    fn collide_packet(&self, rays: &[Ray], mask: u64, closest: &mut [f64], hits: &mut [Option<CollisionInfo>]) {
+        self.tree.traverse_packet(rays, mask, closest, |i, active, closest| {
+            self.placed(i).collide_packet(rays, active, closest, hits);
+        });
+    }
+
+    fn collect_hits(&self, ray: &Ray, max_distance: f64, hits: &mut Vec<CollisionInfo>) {
+        let mut objects: Vec<usize> = Vec::new();
+        self.tree.traverse(ray, max_distance, |i, _| {
+            objects.push(i);
+            None
+        });
+
+        // Objects split by spatial splits are in several leaves
+        objects.sort_unstable();
+        objects.dedup();
+        for i in objects {
+            self.placed(i).collect_hits(ray, max_distance, hits);
+        }
+    }
+
+    fn visit(&self, visitor: &mut dyn ElementVisitor, placement: &Placement) {
+        for object in self.objects.iter().flatten() {
+            object.placed.visit(visitor, placement);
+        }
+    }
+
+    // Only the top level is counted, like the BVHs built with build_bvh
+    fn bvh_stats(&self, stats: &mut BvhStats, depth: usize) {
+        self.tree.stats(stats, depth);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::config::EPSILON;
+use crate::geometry::ray::Ray;
+use crate::scene::bvh::{BvhNode, ItemHit};
+
+// Number of children of a wide node
+const WIDTH: usize = 4;
+// Count of the children that are wide nodes themselves
+const INNER_NODE: u32 = u32::MAX;
+
+// A node of a wide BVH. The bounds of its children are stored axis by axis, so
+// that they can be tested against a ray all at once. Children that are leaves
+// hold count items starting at first, inner children have a count of
+// INNER_NODE and the index of their node in first, and unused children have a
+// count of 0
+#[derive(Clone, Copy)]
+pub struct WideNode {
+    start: [[f32; WIDTH]; 3],
+    end: [[f32; WIDTH]; 3],
+    first: [u32; WIDTH],
+    count: [u32; WIDTH]
+}
+
+impl WideNode {
+    const EMPTY: WideNode = WideNode {
+        start: [[0.; WIDTH]; 3],
+        end: [[0.; WIDTH]; 3],
+        first: [0; WIDTH],
+        count: [0; WIDTH]
+    };
+
+    // Returns how far along the ray each child is entered, or infinity for the
+    // children it misses. The test is the same as HitBox::intersects
+    fn intersect(&self, ray: &WideRay, max_distance: f64) -> [f64; WIDTH] {
+        let mut tmin = [f64::NEG_INFINITY; WIDTH];
+        let mut tmax = [max_distance; WIDTH];
+        let mut inside = [true; WIDTH];
+
+        for axis in 0..3 {
+            let (start, end, origin) = (&self.start[axis], &self.end[axis], ray.origin[axis]);
+            if ray.parallel[axis] {
+                for child in 0..WIDTH {
+                    inside[child] &= origin >= start[child] as f64 && origin <= end[child] as f64;
+                }
+                continue;
+            }
+
+            let inverse = ray.inverse[axis];
+            for child in 0..WIDTH {
+                let t1 = (start[child] as f64 - origin) * inverse;
+                let t2 = (end[child] as f64 - origin) * inverse;
+                tmin[child] = tmin[child].max(t1.min(t2));
+                tmax[child] = tmax[child].min(t1.max(t2));
+            }
+        }
+
+        std::array::from_fn(|child| {
+            let hit = self.count[child] != 0 && inside[child] && tmin[child] <= tmax[child] && tmax[child] >= 0.;
+            if hit { tmin[child] } else { f64::INFINITY }
+        })
+    }
+}
+
+// A ray prepared to be tested against many nodes
+struct WideRay {
+    origin: [f64; 3],
+    inverse: [f64; 3],
+    parallel: [bool; 3]
+}
+
+impl WideRay {
+    fn new(ray: &Ray) -> Self {
+        let direction = [ray.direction.x, ray.direction.y, ray.direction.z];
+        Self {
+            origin: [ray.origin.x, ray.origin.y, ray.origin.z],
+            inverse: direction.map(|d| 1. / d),
+            parallel: direction.map(|d| d.abs() < EPSILON)
+        }
+    }
+}
+
+// Collapses a binary BVH into a wide one with the same leaves
+pub fn collapse(nodes: &[BvhNode]) -> Vec<WideNode> {
+    let mut wide_nodes = Vec::new();
+    if !nodes.is_empty() {
+        add_node(nodes, 0, &mut wide_nodes);
+    }
+    wide_nodes
+}
+
+// Adds the wide node for the binary subtree at index, and returns its index.
+// Its children are found by opening the inner node with the largest surface
+// area among them until there are WIDTH of them
+fn add_node(nodes: &[BvhNode], index: usize, wide_nodes: &mut Vec<WideNode>) -> usize {
+    let mut children = vec![index];
+    while children.len() < WIDTH {
+        let inner = children.iter().enumerate()
+            .filter(|(_, child)| !nodes[**child].is_leaf())
+            .max_by(|(_, a), (_, b)| nodes[**a].hitbox().area().total_cmp(&nodes[**b].hitbox().area()));
+        let Some((position, &child)) = inner else { break };
+
+        let (left, right) = nodes[child].children(child);
+        children[position] = left;
+        children.push(right);
+    }
+
+    let wide_index = wide_nodes.len();
+    wide_nodes.push(WideNode::EMPTY);
+
+    let mut node = WideNode::EMPTY;
+    for (slot, &child) in children.iter().enumerate() {
+        // Bounds were already rounded to f32, so they are converted back exactly
+        let hitbox = nodes[child].hitbox();
+        let (start, end) = (hitbox.start(), hitbox.end());
+        for (axis, (start, end)) in [(start.x, end.x), (start.y, end.y), (start.z, end.z)].into_iter().enumerate() {
+            node.start[axis][slot] = start as f32;
+            node.end[axis][slot] = end as f32;
+        }
+
+        if nodes[child].is_leaf() {
+            let items = nodes[child].items();
+            node.first[slot] = items.start as u32;
+            node.count[slot] = items.len() as u32;
+        } else {
+            node.first[slot] = add_node(nodes, child, wide_nodes) as u32;
+            node.count[slot] = INNER_NODE;
+        }
+    }
+
+    wide_nodes[wide_index] = node;
+    wide_index
+}
+
+// Traverses a wide BVH, see BvhTree::traverse. All the children of a node are
+// tested at once and visited in the order the ray enters them
+pub fn traverse_wide(nodes: &[WideNode], ray: &Ray, max_distance: f64, mut hit: impl FnMut(usize, f64) -> ItemHit) -> f64 {
+    let mut closest = max_distance;
+    if nodes.is_empty() { return closest }
+
+    let wide_ray = WideRay::new(ray);
+    // Children left to visit, with their first, count and how far along the
+    // ray they are entered
+    let mut stack: Vec<(u32, u32, f64)> = Vec::with_capacity(64);
+    stack.push((0, INNER_NODE, f64::NEG_INFINITY));
+
+    while let Some((first, count, entry)) = stack.pop() {
+        if entry > closest { continue }
+
+        if count != INNER_NODE {
+            for i in first as usize..(first + count) as usize {
+                match hit(i, closest) {
+                    ItemHit::Miss => (),
+                    ItemHit::Closer(distance) => closest = distance,
+                    ItemHit::Stop => return closest
+                }
+            }
+            continue;
+        }
+
+        let node = &nodes[first as usize];
+        let entries = node.intersect(&wide_ray, closest);
+        let mut order: [usize; WIDTH] = std::array::from_fn(|child| child);
+        order.sort_unstable_by(|&a, &b| entries[b].total_cmp(&entries[a]));
+        for child in order {
+            if entries[child] < f64::INFINITY {
+                stack.push((node.first[child], node.count[child], entries[child]));
+            }
+        }
+    }
+    closest
+}
@@ -1,2 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod utils;
 pub mod reader;
+pub mod writer;
@@ -1,46 +1,911 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
 use std::sync::Arc;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use crate::config::STL_SMOOTHING_ANGLE;
 use crate::geometry::vector::Vector;
-use crate::scene::materials::Material;
-use crate::scene::elements::{SceneElement, Triangle};
+use crate::geometry::transform::Transform;
+use crate::scene::cache::cached_mesh;
+use crate::scene::materials::{DielectricMaterial, DiffuseMaterial, Material, ReflectiveMaterial, Texture, TexturedMaterial};
+use crate::scene::mesh::{Face, Mesh, MeshBuffers};
 
-pub fn read_obj(filename: &str, material: Arc<dyn Material>) -> Vec<Arc<dyn SceneElement>> {
-    let file = File::open(filename).expect("Couldn't open model file");
+// How materials are assigned to the faces of an .obj file
+enum Materials<'a> {
+    // The same material for every face
+    Single(Arc<dyn Material>),
+    // Materials from the file's material libraries. Faces whose material is
+    // in overrides use that one instead, and faces without a known material
+    // use fallback
+    Library {
+        fallback: Arc<dyn Material>,
+        overrides: &'a HashMap<String, Arc<dyn Material>>
+    }
+}
+
+// An error found while reading an .obj file
+#[derive(Debug)]
+pub enum ObjError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, line: usize, message: String }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ObjError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message)
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+// The faces of an .obj file that belong to the same `o` or `g` statement.
+// Faces that come before any of them are in the "default" group. The meshes of
+// all the groups in a file share their vertices
+pub struct ObjGroup {
+    pub name: String,
+    pub mesh: Mesh
+}
+
+pub fn read_obj(filename: &str, material: Arc<dyn Material>) -> Result<Mesh, ObjError> {
+    read_obj_transformed(filename, material, &Transform::IDENTITY)
+}
+
+// Reads an .obj file and applies transform to its vertices
+pub fn read_obj_transformed(filename: &str, material: Arc<dyn Material>, transform: &Transform) -> Result<Mesh, ObjError> {
+    cached_mesh("obj", Path::new(filename), transform, vec![material.clone()], || {
+        Ok(parse_obj(filename, Materials::Single(material), transform)?.into_mesh())
+    })
+}
+
+// Reads an .obj file, keeping its faces split by object and group
+pub fn read_obj_groups(filename: &str, material: Arc<dyn Material>, transform: &Transform) -> Result<Vec<ObjGroup>, ObjError> {
+    Ok(parse_obj(filename, Materials::Single(material), transform)?.into_groups())
+}
+
+// Reads an .obj file, assigning each face the material named by `usemtl` in
+// the libraries listed by `mtllib`. Names in overrides are mapped to the given
+// materials instead, and faces without a known material use fallback
+pub fn read_obj_with_mtl(
+    filename: &str,
+    fallback: Arc<dyn Material>,
+    overrides: &HashMap<String, Arc<dyn Material>>,
+    transform: &Transform
+) -> Result<Mesh, ObjError> {
+    Ok(parse_obj(filename, Materials::Library { fallback, overrides }, transform)?.into_mesh())
+}
+
+fn parse_obj<'a>(filename: &'a str, materials: Materials<'a>, transform: &'a Transform) -> Result<ObjParser<'a>, ObjError> {
+    let path = Path::new(filename);
+    let source = fs::read_to_string(path).map_err(|error| ObjError::Io {
+        path: path.to_path_buf(),
+        error
+    })?;
+
+    let mut parser = ObjParser::new(path, materials, transform);
+    for (line, statement) in statements(&source) {
+        parser.statement(&statement, line).map_err(|message| ObjError::Parse {
+            path: path.to_path_buf(),
+            line,
+            message
+        })?;
+    }
+
+    Ok(parser)
+}
+
+// Splits an .obj file into statements, removing comments and joining lines
+// that end with a backslash. Each statement comes with the line it starts on
+fn statements(source: &str) -> Vec<(usize, String)> {
+    let mut statements: Vec<(usize, String)> = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim_end();
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false)
+        };
+
+        let (_, statement) = current.get_or_insert_with(|| (i + 1, String::new()));
+        statement.push_str(line);
+        statement.push(' ');
+        if !continues {
+            statements.extend(current.take());
+        }
+    }
+
+    statements.extend(current);
+    statements
+}
+
+struct ObjParser<'a> {
+    path: &'a Path,
+    // How materials are assigned to faces
+    assignment: Materials<'a>,
+    transform: &'a Transform,
+    // Mirroring transforms flip the winding order, which would make triangles
+    // face the wrong way
+    flip: bool,
+
+    buffers: MeshBuffers,
+    // Names and faces of the groups
+    groups: Vec<(String, Vec<Face>)>,
+    // Index of the group new faces are added to
+    group: usize,
+
+    // Materials used by the faces, starting with the default one
+    materials: Vec<Arc<dyn Material>>,
+    // Index of the material of new faces
+    material: u32,
+    library: HashMap<String, MtlMaterial>,
+    // Indexes of the materials already used by name, so that faces share them
+    converted: HashMap<String, u32>,
+    // Unsupported statements that were already warned about
+    ignored: HashSet<String>
+}
+
+impl<'a> ObjParser<'a> {
+    fn new(path: &'a Path, assignment: Materials<'a>, transform: &'a Transform) -> Self {
+        let material = match &assignment {
+            Materials::Single(material) => material.clone(),
+            Materials::Library { fallback, .. } => fallback.clone()
+        };
+
+        Self {
+            path,
+            assignment,
+            transform,
+            flip: transform.flips_orientation(),
+            buffers: MeshBuffers::default(),
+            groups: vec![("default".to_string(), Vec::new())],
+            group: 0,
+            materials: vec![material],
+            material: 0,
+            library: HashMap::new(),
+            converted: HashMap::new(),
+            ignored: HashSet::new()
+        }
+    }
+
+    fn statement(&mut self, statement: &str, line: usize) -> Result<(), String> {
+        let parts: Vec<&str> = statement.split_whitespace().collect();
+        let Some((&keyword, arguments)) = parts.split_first() else { return Ok(()) };
+
+        match keyword {
+            "v" => {
+                let vertex = Vector::new(number(arguments, 0)?, number(arguments, 1)?, number(arguments, 2)?);
+                self.buffers.vertices.push(self.transform.point(&vertex));
+                // Some programs write vertex colors after the position
+                if arguments.len() >= 6 {
+                    let color = Vector::new(number(arguments, 3)?, number(arguments, 4)?, number(arguments, 5)?);
+                    self.buffers.colors.resize(self.buffers.vertices.len() - 1, Vector::uniform(1.));
+                    self.buffers.colors.push(color);
+                }
+            },
+            "vn" => {
+                let normal = Vector::new(number(arguments, 0)?, number(arguments, 1)?, number(arguments, 2)?);
+                self.buffers.normals.push(self.transform.normal(&normal));
+            },
+            "vt" => {
+                let u = number(arguments, 0)?;
+                let v = if arguments.len() > 1 { number(arguments, 1)? } else { 0. };
+                self.buffers.uvs.push((u, v));
+            },
+            "f" => self.face(arguments)?,
+            "o" | "g" => {
+                let name = if arguments.is_empty() { "default".to_string() } else { arguments.join(" ") };
+                self.group = match self.groups.iter().position(|(group, _)| *group == name) {
+                    Some(group) => group,
+                    None => {
+                        self.groups.push((name, Vec::new()));
+                        self.groups.len() - 1
+                    }
+                };
+            },
+            "mtllib" => if let Materials::Library { .. } = &self.assignment {
+                // File names may contain spaces
+                let directory = self.path.parent().unwrap_or(Path::new(""));
+                let path = directory.join(statement.trim()[keyword.len()..].trim());
+                match read_mtl(&path) {
+                    Ok(materials) => self.library.extend(materials),
+                    Err(e) => eprintln!("Couldn't read material library {}: {}", path.display(), e)
+                }
+            },
+            "usemtl" => if let Materials::Library { overrides, .. } = &self.assignment {
+                let name = arguments.first().copied().unwrap_or_default();
+                self.material = match self.converted.get(name) {
+                    Some(&material) => material,
+                    None => {
+                        let material = if let Some(material) = overrides.get(name) {
+                            Some(material.clone())
+                        } else if let Some(mtl) = self.library.get(name) {
+                            Some(mtl.to_material())
+                        } else {
+                            eprintln!("{}:{}: unknown material {}", self.path.display(), line, name);
+                            None
+                        };
+                        // Faces without a known material use the fallback,
+                        // which is the first one
+                        let index = match material {
+                            Some(material) => {
+                                self.materials.push(material);
+                                self.materials.len() as u32 - 1
+                            },
+                            None => 0
+                        };
+                        self.converted.insert(name.to_string(), index);
+                        index
+                    }
+                };
+            },
+            // Smoothing groups don't matter, since normals come from vn
+            "s" => (),
+            _ => if self.ignored.insert(keyword.to_string()) {
+                eprintln!("{}:{}: ignoring unsupported statement `{}`", self.path.display(), line, keyword);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Each vertex is given as v, v/vt, v//vn or v/vt/vn. Faces with more than
+    // three vertices are split into a fan of triangles
+    fn face(&mut self, arguments: &[&str]) -> Result<(), String> {
+        if arguments.len() < 3 {
+            return Err(format!("a face needs at least 3 vertices, found {}", arguments.len()));
+        }
 
-    let mut vertices: Vec<Vector> = Vec::new();
-    let mut triangles: Vec<Arc<dyn SceneElement>> = Vec::new();
+        let mut indexes: Vec<u32> = Vec::new();
+        let mut uv_indexes: Vec<Option<u32>> = Vec::new();
+        let mut normal_indexes: Vec<Option<u32>> = Vec::new();
+        for argument in arguments {
+            let mut references = argument.split('/');
+            let vertex = references.next().unwrap_or_default();
+            let uv = references.next().filter(|n| !n.is_empty());
+            let normal = references.next().filter(|n| !n.is_empty());
+            indexes.push(index(vertex, self.buffers.vertices.len(), "vertex")?);
+            uv_indexes.push(uv.map(|n| index(n, self.buffers.uvs.len(), "texture coordinate")).transpose()?);
+            normal_indexes.push(normal.map(|n| index(n, self.buffers.normals.len(), "normal")).transpose()?);
+        }
+
+        for i in 2..indexes.len() {
+            let (b, c) = if self.flip { (i, i - 1) } else { (i - 1, i) };
+            let corners = [0, b, c];
+
+            // Faces are only smooth or textured if all their vertices have
+            // normals or texture coordinates
+            let normals = match corners.map(|j| normal_indexes[j]) {
+                [Some(na), Some(nb), Some(nc)] => Some([na, nb, nc]),
+                _ => None
+            };
+            let uvs = match corners.map(|j| uv_indexes[j]) {
+                [Some(ua), Some(ub), Some(uc)] => Some([ua, ub, uc]),
+                _ => None
+            };
+            self.groups[self.group].1.push(Face {
+                vertices: corners.map(|j| indexes[j]),
+                normals,
+                uvs,
+                material: self.material
+            });
+        }
+
+        Ok(())
+    }
+
+    // Vertices without a color are white, if any other vertex has one
+    fn pad_colors(&mut self) {
+        if !self.buffers.colors.is_empty() {
+            self.buffers.colors.resize(self.buffers.vertices.len(), Vector::uniform(1.));
+        }
+    }
+
+    // Builds a single mesh out of all the faces
+    fn into_mesh(mut self) -> Mesh {
+        self.pad_colors();
+        let faces = self.groups.into_iter().flat_map(|(_, faces)| faces).collect();
+        Mesh::new(Arc::new(self.buffers), faces, self.materials)
+    }
+
+    // Builds a mesh for each group that has faces
+    fn into_groups(mut self) -> Vec<ObjGroup> {
+        self.pad_colors();
+        let buffers = Arc::new(self.buffers);
+        self.groups.into_iter()
+            .filter(|(_, faces)| !faces.is_empty())
+            .map(|(name, faces)| ObjGroup {
+                name,
+                mesh: Mesh::new(buffers.clone(), faces, self.materials.clone())
+            })
+            .collect()
+    }
+}
+
+fn number(arguments: &[&str], i: usize) -> Result<f64, String> {
+    let argument = arguments.get(i).ok_or_else(|| format!("expected at least {} numbers", i + 1))?;
+    argument.parse().map_err(|_| format!("invalid number `{}`", argument))
+}
+
+// Converts a 1-based .obj index into a 0-based one. Negative indexes count
+// back from the last element read so far
+fn index(reference: &str, count: usize, kind: &str) -> Result<u32, String> {
+    let index: i64 = reference.parse().map_err(|_| format!("invalid {} index `{}`", kind, reference))?;
+    let resolved = if index > 0 { index - 1 } else { count as i64 + index };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{} index {} is out of range, there are {}", kind, index, count));
+    }
+    Ok(resolved as u32)
+}
+
+// A material as described in a .mtl file
+// https://paulbourke.net/dataformats/mtl/
+#[derive(Debug, Clone)]
+pub struct MtlMaterial {
+    // Kd
+    pub diffuse: Vector,
+    // Ks
+    pub specular: Vector,
+    // Ke
+    pub emission: Vector,
+    // Ns, between 0 and 1000
+    pub shininess: f64,
+    // Ni
+    pub refraction_index: f64,
+    // d, where 1 is fully opaque
+    pub dissolve: f64,
+    // illum
+    pub illumination: u32,
+    // map_Kd, joined with the directory of the .mtl file
+    pub diffuse_map: Option<PathBuf>
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        Self {
+            diffuse: Vector::uniform(0.8),
+            specular: Vector::ZERO,
+            emission: Vector::ZERO,
+            shininess: 0.,
+            refraction_index: 1.,
+            dissolve: 1.,
+            illumination: 2,
+            diffuse_map: None
+        }
+    }
+}
+
+impl MtlMaterial {
+    // Maps the material onto the closest caracol material:
+    // - Emissive materials (Ke) become diffuse lights
+    // - Transparent materials (d < 1 or illum 4, 6 or 7) become dielectrics
+    // - Materials with reflections on (illum 3) become reflective, with a
+    //   glossiness given by Ns and the color of Ks, or of Kd without one
+    // - Everything else is diffuse, and textured if it has a map_Kd
+    pub fn to_material(&self) -> Arc<dyn Material> {
+        let emission = self.emission.x.max(self.emission.y).max(self.emission.z);
+        if emission > 0. {
+            return Arc::new(DiffuseMaterial::new(self.emission / emission, emission));
+        }
+
+        if self.dissolve < 1. || matches!(self.illumination, 4 | 6 | 7) {
+            // Ni is often left at 1 by exporters, which would make the material
+            // invisible
+            let refraction_index = if self.refraction_index > 1. { self.refraction_index } else { 1.5 };
+            return Arc::new(DielectricMaterial::new(self.diffuse, refraction_index));
+        }
+
+        if self.illumination == 3 {
+            let glossiness = (self.shininess / 1000.).clamp(0., 1.);
+            let color = if self.specular.is_zero() { self.diffuse } else { self.specular };
+            return Arc::new(ReflectiveMaterial::new(color, 0., glossiness));
+        }
+
+        if let Some(path) = &self.diffuse_map {
+            match Texture::open(path) {
+                Ok(texture) => return Arc::new(TexturedMaterial::new(Arc::new(texture), self.diffuse, 0.)),
+                Err(e) => eprintln!("Couldn't read texture {}: {}", path.display(), e)
+            }
+        }
+
+        Arc::new(DiffuseMaterial::new(self.diffuse, 0.))
+    }
+}
+
+// Reads the materials in a .mtl file. Malformed statements are skipped
+pub fn read_mtl(filename: &Path) -> std::io::Result<HashMap<String, MtlMaterial>> {
+    let file = File::open(filename)?;
+    let directory = filename.parent().unwrap_or(Path::new(""));
+
+    let mut materials: HashMap<String, MtlMaterial> = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
 
     for line in BufReader::new(file).lines() {
-        let line = line.expect("Couldn't parse model file");
+        let line = line?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() || parts[0].starts_with('#') { continue }
+
+        if parts[0] == "newmtl" {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material);
+            }
+            let name = parts.get(1).copied().unwrap_or_default().to_string();
+            current = Some((name, MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, material)) = &mut current else { continue };
+        let number = |i: usize| parts.get(i).and_then(|p| p.parse::<f64>().ok());
+        let color = || Some(Vector::new(number(1)?, number(2)?, number(3)?));
+
+        match parts[0] {
+            "Kd" => if let Some(c) = color() { material.diffuse = c },
+            "Ks" => if let Some(c) = color() { material.specular = c },
+            "Ke" => if let Some(c) = color() { material.emission = c },
+            "Ns" => if let Some(n) = number(1) { material.shininess = n },
+            "Ni" => if let Some(n) = number(1) { material.refraction_index = n },
+            "d" => if let Some(n) = number(1) { material.dissolve = n },
+            "Tr" => if let Some(n) = number(1) { material.dissolve = 1. - n },
+            "illum" => if let Some(n) = number(1) { material.illumination = n as u32 },
+            // Options come before the file name, which is the last part
+            "map_Kd" => if let Some(file) = parts.last() {
+                material.diffuse_map = Some(directory.join(file));
+            },
+            _ => ()
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+
+    Ok(materials)
+}
+
+// An error found while reading a .ply file
+#[derive(Debug)]
+pub enum PlyError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, message: String }
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlyError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            PlyError::Parse { path, message } => write!(f, "{}: {}", path.display(), message)
+        }
+    }
+}
+
+impl std::error::Error for PlyError {}
+
+pub fn read_ply(filename: &str, material: Arc<dyn Material>) -> Result<Mesh, PlyError> {
+    read_ply_transformed(filename, material, &Transform::IDENTITY)
+}
+
+// Reads a .ply file, in ASCII or binary, and applies transform to its vertices.
+// Vertex normals and colors are read if present, and polygons are split into
+// triangles
+// https://paulbourke.net/dataformats/ply/
+pub fn read_ply_transformed(filename: &str, material: Arc<dyn Material>, transform: &Transform) -> Result<Mesh, PlyError> {
+    let path = Path::new(filename);
+    cached_mesh("ply", path, transform, vec![material.clone()], || {
+        let bytes = fs::read(path).map_err(|error| PlyError::Io {
+            path: path.to_path_buf(),
+            error
+        })?;
+        parse_ply(&bytes, material, transform).map_err(|message| PlyError::Parse {
+            path: path.to_path_buf(),
+            message
+        })
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    I8, U8, I16, U16, I32, U32, F32, F64
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return Err(format!("unknown property type `{}`", name))
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8
+        }
+    }
+
+    // Colors are stored either as integers or as numbers between 0 and 1
+    fn color_scale(self) -> f64 {
+        match self {
+            PlyType::U16 => 65535.,
+            PlyType::F32 | PlyType::F64 => 1.,
+            _ => 255.
+        }
+    }
+}
+
+enum PlyProperty {
+    Scalar { name: String, kind: PlyType },
+    List { name: String, count: PlyType, item: PlyType }
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>
+}
+
+// Reads the values of the body of a .ply file one by one
+struct PlyBody<'a> {
+    format: PlyFormat,
+    bytes: &'a [u8],
+    position: usize,
+    tokens: std::str::SplitAsciiWhitespace<'a>
+}
+
+impl PlyBody<'_> {
+    fn value(&mut self, kind: PlyType) -> Result<f64, String> {
+        if self.format == PlyFormat::Ascii {
+            let token = self.tokens.next().ok_or("unexpected end of file")?;
+            return token.parse().map_err(|_| format!("invalid number `{}`", token));
+        }
+
+        let size = kind.size();
+        let bytes = self.bytes.get(self.position..self.position + size).ok_or("unexpected end of file")?;
+        self.position += size;
+
+        let mut buffer = [0; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.format == PlyFormat::BigEndian { buffer[..size].reverse(); }
+        let [b0, b1, b2, b3, ..] = buffer;
+
+        Ok(match kind {
+            PlyType::I8 => b0 as i8 as f64,
+            PlyType::U8 => b0 as f64,
+            PlyType::I16 => i16::from_le_bytes([b0, b1]) as f64,
+            PlyType::U16 => u16::from_le_bytes([b0, b1]) as f64,
+            PlyType::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            PlyType::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            PlyType::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+            PlyType::F64 => f64::from_le_bytes(buffer)
+        })
+    }
+}
+
+fn parse_ply(bytes: &[u8], material: Arc<dyn Material>, transform: &Transform) -> Result<Mesh, String> {
+    // The header is always text, and ends with an end_header line
+    let mut format: Option<PlyFormat> = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    let mut position = 0;
+    let mut number = 0;
+
+    loop {
+        let end = bytes[position..].iter().position(|&b| b == b'\n')
+            .ok_or("the header has no end_header line")?;
+        let line = String::from_utf8_lossy(&bytes[position..position + end]);
+        position += end + 1;
+        number += 1;
+
         let parts: Vec<&str> = line.split_whitespace().collect();
-        
-        if parts[0] == "v" {
-            let x: f64 = parts[1].parse().unwrap();
-            let y: f64 = parts[2].parse().unwrap();
-            let z: f64 = parts[3].parse().unwrap();
-            vertices.push(Vector::new(x, y, z));
-
-        } else if parts[0] == "f" {
-            let mut indexes: Vec<usize> = Vec::new();
-            for i in 1..parts.len() {
-                let vertex = parts[i].split("/").next().unwrap();
-                let index: usize = vertex.parse().unwrap();
-                // Vertices are 1-indexed in .obj files
-                indexes.push(index - 1);
+        let error = |message: &str| format!("header line {}: {}", number, message);
+        match parts.as_slice() {
+            ["ply"] if number == 1 => (),
+            _ if number == 1 => return Err("not a .ply file".to_string()),
+            ["format", name, _] => format = Some(match *name {
+                "ascii" => PlyFormat::Ascii,
+                "binary_little_endian" => PlyFormat::LittleEndian,
+                "binary_big_endian" => PlyFormat::BigEndian,
+                _ => return Err(error(&format!("unknown format `{}`", name)))
+            }),
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| error(&format!("invalid element count `{}`", count)))?,
+                properties: Vec::new()
+            }),
+            ["property", "list", count, item, name] => {
+                let element = elements.last_mut().ok_or_else(|| error("property outside of an element"))?;
+                element.properties.push(PlyProperty::List {
+                    name: name.to_string(),
+                    count: PlyType::parse(count).map_err(|e| error(&e))?,
+                    item: PlyType::parse(item).map_err(|e| error(&e))?
+                });
+            },
+            ["property", kind, name] => {
+                let element = elements.last_mut().ok_or_else(|| error("property outside of an element"))?;
+                element.properties.push(PlyProperty::Scalar {
+                    name: name.to_string(),
+                    kind: PlyType::parse(kind).map_err(|e| error(&e))?
+                });
+            },
+            ["end_header"] => break,
+            ["comment", ..] | ["obj_info", ..] | [] => (),
+            _ => return Err(error(&format!("unexpected `{}`", line.trim())))
+        }
+    }
+
+    let format = format.ok_or("the header has no format line")?;
+    let text = if format == PlyFormat::Ascii {
+        std::str::from_utf8(&bytes[position..]).map_err(|_| "the ASCII body isn't valid text")?
+    } else {
+        ""
+    };
+    let mut body = PlyBody { format, bytes, position, tokens: text.split_ascii_whitespace() };
+
+    // Mirroring transforms flip the winding order, which would make triangles
+    // face the wrong way
+    let flip = transform.flips_orientation();
+    let mut buffers = MeshBuffers::default();
+    let mut polygons: Vec<Vec<u32>> = Vec::new();
+
+    for element in &elements {
+        for record in 0..element.count {
+            let error = |e: String| format!("{} {}: {}", element.name, record, e);
+            let mut position = [0.; 3];
+            let mut normal: [Option<f64>; 3] = [None; 3];
+            let mut color: [Option<f64>; 3] = [None; 3];
+            let mut indexes: Vec<u32> = Vec::new();
+
+            for property in &element.properties {
+                match property {
+                    PlyProperty::Scalar { name, kind } => {
+                        let value = body.value(*kind).map_err(error)?;
+                        match name.as_str() {
+                            "x" => position[0] = value,
+                            "y" => position[1] = value,
+                            "z" => position[2] = value,
+                            "nx" => normal[0] = Some(value),
+                            "ny" => normal[1] = Some(value),
+                            "nz" => normal[2] = Some(value),
+                            "red" => color[0] = Some(value / kind.color_scale()),
+                            "green" => color[1] = Some(value / kind.color_scale()),
+                            "blue" => color[2] = Some(value / kind.color_scale()),
+                            _ => ()
+                        }
+                    },
+                    PlyProperty::List { name, count, item } => {
+                        let count = body.value(*count).map_err(error)? as usize;
+                        for _ in 0..count {
+                            let value = body.value(*item).map_err(error)?;
+                            if name == "vertex_indices" || name == "vertex_index" {
+                                if value < 0. { return Err(error(format!("negative vertex index {}", value))) }
+                                indexes.push(value as u32);
+                            }
+                        }
+                    }
+                }
             }
 
-            for i in 2..indexes.len() {
-                triangles.push(Arc::new(Triangle::new(
-                    vertices[indexes[0]],
-                    vertices[indexes[i - 1]],
-                    vertices[indexes[i]],
-                    material.clone()
-                )));
+            match element.name.as_str() {
+                "vertex" => {
+                    let [x, y, z] = position;
+                    buffers.vertices.push(transform.point(&Vector::new(x, y, z)));
+                    if let [Some(x), Some(y), Some(z)] = normal {
+                        buffers.normals.push(transform.normal(&Vector::new(x, y, z)));
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        buffers.colors.push(Vector::new(r, g, b));
+                    }
+                },
+                "face" => polygons.push(indexes),
+                _ => ()
             }
         }
     }
 
-    triangles
+    let count = buffers.vertices.len();
+    let has_normals = buffers.normals.len() == count;
+    if buffers.colors.len() != count { buffers.colors.clear(); }
+
+    let mut faces: Vec<Face> = Vec::new();
+    for (i, polygon) in polygons.iter().enumerate() {
+        if let Some(index) = polygon.iter().find(|&&index| index as usize >= count) {
+            return Err(format!("face {} uses vertex {}, but there are {}", i, index, count));
+        }
+        for j in 2..polygon.len() {
+            let (b, c) = if flip { (j, j - 1) } else { (j - 1, j) };
+            let vertices = [polygon[0], polygon[b], polygon[c]];
+            faces.push(Face {
+                vertices,
+                normals: has_normals.then_some(vertices),
+                uvs: None,
+                material: 0
+            });
+        }
+    }
+
+    Ok(Mesh::new(Arc::new(buffers), faces, vec![material]))
+}
+
+// An error found while reading an .stl file
+#[derive(Debug)]
+pub enum StlError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, message: String }
+}
+
+impl fmt::Display for StlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StlError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            StlError::Parse { path, message } => write!(f, "{}: {}", path.display(), message)
+        }
+    }
+}
+
+impl std::error::Error for StlError {}
+
+pub fn read_stl(filename: &str, material: Arc<dyn Material>) -> Result<Mesh, StlError> {
+    read_stl_transformed(filename, material, &Transform::IDENTITY)
+}
+
+// Reads an .stl file, in ASCII or binary, and applies transform to its
+// vertices. STL stores every triangle on its own, so vertices at the same
+// position are merged to generate smooth normals
+// https://en.wikipedia.org/wiki/STL_(file_format)
+pub fn read_stl_transformed(filename: &str, material: Arc<dyn Material>, transform: &Transform) -> Result<Mesh, StlError> {
+    let path = Path::new(filename);
+    let kind = format!("stl {}", STL_SMOOTHING_ANGLE);
+    cached_mesh(&kind, path, transform, vec![material.clone()], || {
+        let bytes = fs::read(path).map_err(|error| StlError::Io {
+            path: path.to_path_buf(),
+            error
+        })?;
+        let triangles = parse_stl(&bytes).map_err(|message| StlError::Parse {
+            path: path.to_path_buf(),
+            message
+        })?;
+        Ok(stl_mesh(&triangles, material, transform))
+    })
+}
+
+fn parse_stl(bytes: &[u8]) -> Result<Vec<[Vector; 3]>, String> {
+    // Binary files may also start with "solid", so their size is checked first.
+    // They have an 80 byte header, the number of triangles, and 50 bytes per
+    // triangle
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if bytes.len() == 84 + 50 * count {
+            let number = |offset: usize| {
+                f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as f64
+            };
+            let vertex = |offset: usize| Vector::new(number(offset), number(offset + 4), number(offset + 8));
+            // Each triangle starts with its normal, which is ignored
+            return Ok((0..count).map(|i| {
+                let offset = 84 + 50 * i + 12;
+                [vertex(offset), vertex(offset + 12), vertex(offset + 24)]
+            }).collect());
+        }
+    }
+
+    if !bytes.starts_with(b"solid") {
+        return Err("not an .stl file, or a truncated binary one".to_string());
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| "the ASCII file isn't valid text")?;
+
+    let mut triangles: Vec<[Vector; 3]> = Vec::new();
+    let mut polygon: Vec<Vector> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let error = |message: String| format!("line {}: {}", i + 1, message);
+        match parts.first().copied() {
+            Some("vertex") => {
+                let arguments = &parts[1..];
+                let coordinate = |i: usize| number(arguments, i).map_err(error);
+                polygon.push(Vector::new(coordinate(0)?, coordinate(1)?, coordinate(2)?));
+            },
+            Some("endloop") => {
+                if polygon.len() < 3 {
+                    return Err(error(format!("a facet needs at least 3 vertices, found {}", polygon.len())));
+                }
+                for j in 2..polygon.len() {
+                    triangles.push([polygon[0], polygon[j - 1], polygon[j]]);
+                }
+                polygon.clear();
+            },
+            _ => ()
+        }
+    }
+
+    Ok(triangles)
+}
+
+// Merges the vertices of the triangles and gives each corner the average
+// normal of the faces around its vertex, leaving out those at a sharper angle
+// than STL_SMOOTHING_ANGLE
+fn stl_mesh(triangles: &[[Vector; 3]], material: Arc<dyn Material>, transform: &Transform) -> Mesh {
+    // Mirroring transforms flip the winding order, which would make triangles
+    // face the wrong way
+    let flip = transform.flips_orientation();
+    // -0 and 0 must be the same vertex
+    let key = |v: &Vector| [(v.x + 0.).to_bits(), (v.y + 0.).to_bits(), (v.z + 0.).to_bits()];
+
+    let mut buffers = MeshBuffers::default();
+    let mut indexes: HashMap<[u64; 3], u32> = HashMap::new();
+    let mut corners: Vec<[u32; 3]> = Vec::new();
+    let mut normals: Vec<Vector> = Vec::new();
+
+    for triangle in triangles {
+        let [a, b, c] = *triangle;
+        let mut face = [0; 3];
+        for (index, vertex) in face.iter_mut().zip(if flip { [a, c, b] } else { [a, b, c] }) {
+            *index = *indexes.entry(key(&vertex)).or_insert_with(|| {
+                buffers.vertices.push(transform.point(&vertex));
+                buffers.vertices.len() as u32 - 1
+            });
+        }
+
+        // Faces without area can't be hit, and have no normal
+        let [a, b, c] = face.map(|i| buffers.vertices[i as usize]);
+        let normal = (b - a).cross(&(c - a));
+        if normal.is_zero() { continue }
+        corners.push(face);
+        normals.push(normal);
+    }
+
+    let mut around: Vec<Vec<usize>> = vec![Vec::new(); buffers.vertices.len()];
+    for (i, face) in corners.iter().enumerate() {
+        for &vertex in face {
+            around[vertex as usize].push(i);
+        }
+    }
+
+    // Larger faces weigh more in the average, since their normals are longer
+    let units: Vec<Vector> = normals.iter().map(|normal| normal.normalize()).collect();
+    let threshold = STL_SMOOTHING_ANGLE.to_radians().cos();
+    // Corners of the same vertex with the same normal share it
+    let mut shared: HashMap<(u32, [u64; 3]), u32> = HashMap::new();
+    let mut faces: Vec<Face> = Vec::new();
+
+    for (i, face) in corners.iter().enumerate() {
+        let mut corner_normals = [0; 3];
+        for (index, &vertex) in corner_normals.iter_mut().zip(face) {
+            let mut normal = Vector::ZERO;
+            for &j in &around[vertex as usize] {
+                if units[j].dot(&units[i]) >= threshold { normal += normals[j]; }
+            }
+            let normal = normal.normalize();
+            *index = *shared.entry((vertex, key(&normal))).or_insert_with(|| {
+                buffers.normals.push(normal);
+                buffers.normals.len() as u32 - 1
+            });
+        }
+
+        faces.push(Face {
+            vertices: *face,
+            normals: Some(corner_normals),
+            uvs: None,
+            material: 0
+        });
+    }
+
+    Mesh::new(Arc::new(buffers), faces, vec![material])
 }
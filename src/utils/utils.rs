@@ -1,6 +1,61 @@
+use std::cell::RefCell;
 use image::Rgb;
+use rand::{Rng, SeedableRng};
+use rand::distr::{Distribution, StandardUniform};
+use rand::rngs::StdRng;
 use crate::geometry::vector::Vector;
 
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_os_rng());
+}
+
+// Returns a random value from the current thread's generator
+pub fn random<T>() -> T where StandardUniform: Distribution<T> {
+    RNG.with(|rng| rng.borrow_mut().random())
+}
+
+// Reseeds the current thread's generator, which makes the following calls to
+// random deterministic
+pub fn seed_random(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+// Runs f with rng as the current thread's generator, so that several
+// sequences of random values can be interleaved on the same thread
+pub fn with_random<T>(rng: &mut StdRng, f: impl FnOnce() -> T) -> T {
+    // Swaps the generators back when dropped, even if f panics
+    struct Swapped<'a>(&'a mut StdRng);
+
+    impl Drop for Swapped<'_> {
+        fn drop(&mut self) {
+            RNG.with(|current| std::mem::swap(&mut *current.borrow_mut(), self.0));
+        }
+    }
+
+    RNG.with(|current| std::mem::swap(&mut *current.borrow_mut(), rng));
+    let _swapped = Swapped(rng);
+    f()
+}
+
+// A generator seeded like seed_random does, or from the current thread's
+// generator without a seed
+pub fn new_random(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => RNG.with(|rng| StdRng::from_rng(&mut *rng.borrow_mut()))
+    }
+}
+
+// The indexes of the bits set in mask, lowest first
+pub fn set_bits(mut mask: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if mask == 0 { return None }
+        let i = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+        Some(i)
+    })
+}
+
 pub fn to_rgb(vector: &Vector) -> Rgb<u8> {
     Rgb([(vector.x * 255.) as u8, (vector.y * 255.) as u8, (vector.z * 255.) as u8])
 }
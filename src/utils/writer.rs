@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::geometry::transform::Transform;
+use crate::geometry::vector::Vector;
+use crate::scene::elements::{ElementVisitor, Placement, SceneElement};
+use crate::scene::loader::Scene;
+use crate::scene::materials::{Material, MaterialDescription, Texture};
+use crate::scene::mesh::{Face, Mesh};
+
+// Number of segments around spheres and of rings from pole to pole, when they
+// are turned into triangles
+const SPHERE_SEGMENTS: u32 = 32;
+const SPHERE_RINGS: u32 = 16;
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io { path: PathBuf, error: io::Error },
+    Image { path: PathBuf, error: image::ImageError }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ExportError::Image { path, error } => write!(f, "{}: {}", path.display(), error)
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+// Writes the elements to an .obj file, with their materials in an .mtl file
+// next to it and their textures as .png files. Transforms are applied to the
+// geometry and spheres are turned into triangles
+pub fn write_obj(filename: &str, elements: &[Arc<dyn SceneElement>]) -> Result<(), ExportError> {
+    let path = Path::new(filename);
+    let collector = Collector::collect(elements, true);
+    let mut textures = TextureFiles::new(path);
+
+    let mut names = MaterialNames::default();
+    for (material, _) in &collector.materials {
+        let description = mtl_material(material.as_ref(), &mut textures)?;
+        names.add(description);
+    }
+
+    let mtl_path = path.with_extension("mtl");
+    write_file(&mtl_path, |file| {
+        writeln!(file, "# Exported by caracol")?;
+        for (name, description) in names.unique() {
+            writeln!(file, "\nnewmtl {}", name)?;
+            write!(file, "{}", description)?;
+        }
+        Ok(())
+    })?;
+
+    let mtl_name = mtl_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    write_parts(path, &collector.named_parts(), &names.names, Some(&mtl_name))
+}
+
+// Writes the scene as a scene file that load_scene can read back. Meshes are
+// written to .obj files and textures to .png files next to it, named after it.
+// Transforms are applied to the geometry, so instances become separate meshes.
+// Materials that scene files can't describe are approximated
+pub fn write_scene(filename: &str, scene: &Scene) -> Result<(), ExportError> {
+    let path = Path::new(filename);
+    let collector = Collector::collect(&scene.elements, false);
+    let mut textures = TextureFiles::new(path);
+
+    let mut names = MaterialNames::default();
+    for (material, two_sided) in &collector.materials {
+        let description = toml_material(material.as_ref(), *two_sided, &mut textures)?;
+        names.add(description);
+    }
+
+    let mut meshes: Vec<(String, &Part)> = Vec::new();
+    for (i, (_, part)) in collector.named_parts().into_iter().enumerate() {
+        let file = sibling(path, &format!("mesh{}", i), "obj");
+        write_parts(&file, &[("default".to_string(), part)], &names.names, None)?;
+        meshes.push((file_name(&file), part));
+    }
+
+    let render = &scene.render;
+    let camera = &scene.camera;
+    write_file(path, |file| {
+        writeln!(file, "# Exported by caracol")?;
+        writeln!(file, "[render]")?;
+        writeln!(file, "width = {}", render.width)?;
+        writeln!(file, "height = {}", render.height)?;
+        writeln!(file, "rays_per_pixel = {}", render.rays_per_pixel)?;
+        writeln!(file, "bounces = {}", render.bounces)?;
+        writeln!(file, "background = {}", toml_vector(render.void))?;
+        if let Some(seed) = render.seed {
+            writeln!(file, "seed = {}", seed)?;
+        }
+
+        writeln!(file, "\n[camera]")?;
+        writeln!(file, "position = {}", toml_vector(camera.position))?;
+        writeln!(file, "look_at = {}", toml_vector(camera.look_at))?;
+        writeln!(file, "up = {}", toml_vector(camera.view_up))?;
+        writeln!(file, "fov = {:?}", camera.fov_angle.to_degrees())?;
+        writeln!(file, "defocus_angle = {:?}", camera.defocus_angle.to_degrees())?;
+
+        for (name, description) in names.unique() {
+            writeln!(file, "\n[materials.{}]", name)?;
+            write!(file, "{}", description)?;
+        }
+
+        for (mesh, part) in &meshes {
+            writeln!(file, "\n[[meshes]]")?;
+            writeln!(file, "file = \"{}\"", mesh)?;
+            let mut used: Vec<&str> = part.faces.iter().map(|face| names.names[face.material as usize].as_str()).collect();
+            used.sort();
+            used.dedup();
+            match used.as_slice() {
+                [material] => writeln!(file, "material = \"{}\"", material)?,
+                // Every usemtl name is mapped to the material with that name
+                _ => {
+                    let overrides: Vec<String> = used.iter().map(|name| format!("{0} = \"{0}\"", name)).collect();
+                    writeln!(file, "use_mtl = true")?;
+                    writeln!(file, "material_overrides = {{ {} }}", overrides.join(", "))?;
+                }
+            }
+        }
+
+        for (center, radius, material) in &collector.spheres {
+            writeln!(file, "\n[[spheres]]")?;
+            writeln!(file, "center = {}", toml_vector(*center))?;
+            writeln!(file, "radius = {:?}", radius)?;
+            writeln!(file, "material = \"{}\"", names.names[*material])?;
+        }
+
+        // Vertex normals and texture coordinates of loose triangles are lost
+        for triangle in &collector.triangles {
+            let [a, b, c] = triangle.vertices.map(toml_vector);
+            writeln!(file, "\n[[triangles]]")?;
+            writeln!(file, "vertices = [{}, {}, {}]", a, b, c)?;
+            writeln!(file, "material = \"{}\"", names.names[triangle.material])?;
+        }
+        Ok(())
+    })
+}
+
+// Geometry in scene space, with materials as indexes into the collector's
+struct LooseTriangle {
+    vertices: [Vector; 3],
+    normals: Option<[Vector; 3]>,
+    uvs: Option<[(f64, f64); 3]>,
+    material: usize
+}
+
+// An indexed triangle mesh, like the contents of a Mesh. Colors are either
+// empty or one per vertex
+#[derive(Default)]
+struct Part {
+    vertices: Vec<Vector>,
+    normals: Vec<Vector>,
+    uvs: Vec<(f64, f64)>,
+    colors: Vec<Vector>,
+    faces: Vec<Face>
+}
+
+// Gathers the geometry of elements in scene space
+struct Collector {
+    // Whether spheres and loose triangles are turned into meshes, since .obj
+    // files have neither. Spheres that a transform stretches always are
+    tessellate: bool,
+    // Materials, and whether the geometry they are on is hit from behind
+    materials: Vec<(Arc<dyn Material>, bool)>,
+    // Indexes of the materials by address, so that shared ones are only
+    // exported once
+    indexes: HashMap<(*const (), bool), usize>,
+    spheres: Vec<(Vector, f64, usize)>,
+    triangles: Vec<LooseTriangle>,
+    meshes: Vec<Part>,
+    tessellated: Vec<Part>,
+    loose: Part
+}
+
+impl Collector {
+    fn collect(elements: &[Arc<dyn SceneElement>], tessellate: bool) -> Self {
+        let mut collector = Self {
+            tessellate,
+            materials: Vec::new(),
+            indexes: HashMap::new(),
+            spheres: Vec::new(),
+            triangles: Vec::new(),
+            meshes: Vec::new(),
+            tessellated: Vec::new(),
+            loose: Part::default()
+        };
+        for element in elements {
+            element.visit(&mut collector, &Placement::default());
+        }
+        collector
+    }
+
+    fn material(&mut self, material: &Arc<dyn Material>, placement: &Placement) -> usize {
+        // Back faces are culled by the element's own material, even when an
+        // instance replaces it
+        let two_sided = material.double_sided();
+        let material = placement.material.as_ref().unwrap_or(material);
+        let key = (Arc::as_ptr(material) as *const (), two_sided);
+        if let Some(&index) = self.indexes.get(&key) {
+            return index;
+        }
+        self.materials.push((material.clone(), two_sided));
+        self.indexes.insert(key, self.materials.len() - 1);
+        self.materials.len() - 1
+    }
+
+    // Every mesh with a name for .obj files. Loose triangles are grouped into
+    // a single mesh
+    fn named_parts(&self) -> Vec<(String, &Part)> {
+        let mut parts: Vec<(String, &Part)> = Vec::new();
+        parts.extend(self.meshes.iter().enumerate().map(|(i, part)| (format!("mesh{}", i), part)));
+        parts.extend(self.tessellated.iter().enumerate().map(|(i, part)| (format!("sphere{}", i), part)));
+        if !self.loose.faces.is_empty() {
+            parts.push(("triangles".to_string(), &self.loose));
+        }
+        parts
+    }
+}
+
+impl ElementVisitor for Collector {
+    fn sphere(&mut self, center: Vector, radius: f64, material: &Arc<dyn Material>, placement: &Placement) {
+        let material = self.material(material, placement) as u32;
+        let transform = &placement.transform;
+        if !self.tessellate && let Some(scale) = uniform_scale(transform) {
+            self.spheres.push((transform.point(&center), radius * scale, material as usize));
+            return;
+        }
+        self.tessellated.push(sphere_part(center, radius, material, transform));
+    }
+
+    fn triangle(&mut self, vertices: [Vector; 3], normals: Option<[Vector; 3]>, uvs: Option<[(f64, f64); 3]>, material: &Arc<dyn Material>, placement: &Placement) {
+        let material = self.material(material, placement);
+        let transform = &placement.transform;
+        let mut triangle = LooseTriangle {
+            vertices: vertices.map(|vertex| transform.point(&vertex)),
+            normals: normals.map(|normals| normals.map(|normal| transform.normal(&normal).normalize())),
+            uvs,
+            material
+        };
+        if transform.flips_orientation() {
+            triangle.vertices.swap(1, 2);
+            if let Some(normals) = &mut triangle.normals { normals.swap(1, 2); }
+            if let Some(uvs) = &mut triangle.uvs { uvs.swap(1, 2); }
+        }
+
+        if !self.tessellate {
+            self.triangles.push(triangle);
+            return;
+        }
+        let part = &mut self.loose;
+        let first = part.vertices.len() as u32;
+        part.vertices.extend(triangle.vertices);
+        let normal_first = part.normals.len() as u32;
+        part.normals.extend(triangle.normals.iter().flatten());
+        let uv_first = part.uvs.len() as u32;
+        part.uvs.extend(triangle.uvs.iter().flatten());
+        part.faces.push(Face {
+            vertices: [first, first + 1, first + 2],
+            normals: triangle.normals.map(|_| [normal_first, normal_first + 1, normal_first + 2]),
+            uvs: triangle.uvs.map(|_| [uv_first, uv_first + 1, uv_first + 2]),
+            material: material as u32
+        });
+    }
+
+    fn mesh(&mut self, mesh: &Mesh, placement: &Placement) {
+        let materials: Vec<u32> = mesh.materials().iter()
+            .map(|material| self.material(material, placement) as u32)
+            .collect();
+        let buffers = mesh.buffers();
+        let transform = &placement.transform;
+        let flip = transform.flips_orientation();
+        let colored = !buffers.colors.is_empty();
+
+        // Only the vertices used by the faces are exported, since buffers may
+        // be shared with other meshes
+        let mut vertex_map = vec![u32::MAX; buffers.vertices.len()];
+        let mut normal_map = vec![u32::MAX; buffers.normals.len()];
+        let mut uv_map = vec![u32::MAX; buffers.uvs.len()];
+        let mut part = Part::default();
+
+        for face in mesh.faces() {
+            let mut vertices = face.vertices.map(|i| remap(&mut vertex_map, i, &mut part.vertices, || {
+                if colored { part.colors.push(buffers.colors[i as usize]); }
+                transform.point(&buffers.vertices[i as usize])
+            }));
+            let mut normals = face.normals.map(|normals| normals.map(|i| remap(&mut normal_map, i, &mut part.normals, || {
+                transform.normal(&buffers.normals[i as usize]).normalize()
+            })));
+            let mut uvs = face.uvs.map(|uvs| uvs.map(|i| remap(&mut uv_map, i, &mut part.uvs, || buffers.uvs[i as usize])));
+
+            if flip {
+                vertices.swap(1, 2);
+                if let Some(normals) = &mut normals { normals.swap(1, 2); }
+                if let Some(uvs) = &mut uvs { uvs.swap(1, 2); }
+            }
+            part.faces.push(Face { vertices, normals, uvs, material: materials[face.material as usize] });
+        }
+        self.meshes.push(part);
+    }
+}
+
+// Returns the new index of index in map, copying the value it refers to the
+// first time it is seen
+fn remap<T>(map: &mut [u32], index: u32, values: &mut Vec<T>, value: impl FnOnce() -> T) -> u32 {
+    let slot = &mut map[index as usize];
+    if *slot == u32::MAX {
+        *slot = values.len() as u32;
+        values.push(value());
+    }
+    *slot
+}
+
+// Returns the scale factor of transforms that keep spheres round
+fn uniform_scale(transform: &Transform) -> Option<f64> {
+    let [x, y, z] = [Vector::new(1., 0., 0.), Vector::new(0., 1., 0.), Vector::new(0., 0., 1.)]
+        .map(|axis| transform.vector(&axis));
+    let scale = x.magnitude();
+    let tolerance = 1e-9 * scale;
+    let round = (y.magnitude() - scale).abs() <= tolerance
+        && (z.magnitude() - scale).abs() <= tolerance
+        && x.dot(&y).abs() <= tolerance * scale
+        && x.dot(&z).abs() <= tolerance * scale
+        && y.dot(&z).abs() <= tolerance * scale;
+    round.then_some(scale)
+}
+
+// A UV sphere, with triangles counter-clockwise seen from outside
+fn sphere_part(center: Vector, radius: f64, material: u32, transform: &Transform) -> Part {
+    let mut part = Part::default();
+    for ring in 0..=SPHERE_RINGS {
+        let theta = PI * ring as f64 / SPHERE_RINGS as f64;
+        for segment in 0..=SPHERE_SEGMENTS {
+            let phi = 2. * PI * segment as f64 / SPHERE_SEGMENTS as f64;
+            let normal = Vector::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            part.vertices.push(transform.point(&(center + radius * normal)));
+            part.normals.push(transform.normal(&normal).normalize());
+            part.uvs.push((segment as f64 / SPHERE_SEGMENTS as f64, 1. - ring as f64 / SPHERE_RINGS as f64));
+        }
+    }
+
+    let flip = transform.flips_orientation();
+    let mut triangle = |mut corners: [u32; 3]| {
+        if flip { corners.swap(1, 2); }
+        part.faces.push(Face { vertices: corners, normals: Some(corners), uvs: Some(corners), material });
+    };
+    for ring in 0..SPHERE_RINGS {
+        for segment in 0..SPHERE_SEGMENTS {
+            let a = ring * (SPHERE_SEGMENTS + 1) + segment;
+            let b = a + SPHERE_SEGMENTS + 1;
+            // Skip the triangles that collapse into the poles
+            if ring > 0 { triangle([a, a + 1, b + 1]); }
+            if ring < SPHERE_RINGS - 1 { triangle([a, b + 1, b]); }
+        }
+    }
+    part
+}
+
+// Names materials by what they're written as, so that identical ones share a
+// name
+#[derive(Default)]
+struct MaterialNames {
+    // Name of each collected material
+    names: Vec<String>,
+    // Descriptions by name, in order
+    descriptions: Vec<String>,
+    named: HashMap<String, String>
+}
+
+impl MaterialNames {
+    fn add(&mut self, description: String) {
+        let name = match self.named.get(&description) {
+            Some(name) => name.clone(),
+            None => {
+                let name = format!("material{}", self.descriptions.len());
+                self.named.insert(description.clone(), name.clone());
+                self.descriptions.push(description);
+                name
+            }
+        };
+        self.names.push(name);
+    }
+
+    fn unique(&self) -> impl Iterator<Item = (String, &String)> {
+        self.descriptions.iter().enumerate().map(|(i, description)| (format!("material{}", i), description))
+    }
+}
+
+// Writes textures next to an exported file the first time they are used
+struct TextureFiles {
+    path: PathBuf,
+    files: HashMap<*const Texture, String>
+}
+
+impl TextureFiles {
+    fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), files: HashMap::new() }
+    }
+
+    // Returns the file name of the texture, relative to the exported file
+    fn file(&mut self, texture: &Arc<Texture>) -> Result<String, ExportError> {
+        let key = Arc::as_ptr(texture);
+        if let Some(file) = self.files.get(&key) {
+            return Ok(file.clone());
+        }
+
+        let path = sibling(&self.path, &format!("texture{}", self.files.len()), "png");
+        texture.to_image().save(&path).map_err(|error| ExportError::Image { path: path.clone(), error })?;
+        let file = file_name(&path);
+        self.files.insert(key, file.clone());
+        Ok(file)
+    }
+}
+
+// Describes a material as .mtl statements, in a way that
+// MtlMaterial::to_material reads back
+fn mtl_material(material: &dyn Material, textures: &mut TextureFiles) -> Result<String, ExportError> {
+    let mut lines: Vec<String> = Vec::new();
+    let emission = |lines: &mut Vec<String>, emitted: Vector| if !emitted.is_zero() {
+        lines.push(format!("Ke {}", mtl_vector(emitted)));
+    };
+
+    match material.description() {
+        // .mtl files have no two sided materials, so only the front is kept
+        MaterialDescription::TwoSided(material) => return mtl_material(material, textures),
+        MaterialDescription::Diffuse { color, emission: intensity } => {
+            emission(&mut lines, color * intensity);
+            lines.push(format!("Kd {}", mtl_vector(color)));
+            lines.push("illum 1".to_string());
+        },
+        MaterialDescription::Reflective { color, emission: intensity, glossiness } => {
+            emission(&mut lines, color * intensity);
+            lines.push(format!("Kd {}", mtl_vector(color)));
+            lines.push(format!("Ks {}", mtl_vector(color)));
+            lines.push(format!("Ns {}", glossiness * 1000.));
+            lines.push("illum 3".to_string());
+        },
+        MaterialDescription::Dielectric { color, refraction_index } => {
+            lines.push(format!("Kd {}", mtl_vector(color)));
+            lines.push(format!("Ni {}", refraction_index));
+            lines.push("illum 7".to_string());
+        },
+        MaterialDescription::Textured { texture, tint, emission: intensity } => {
+            emission(&mut lines, tint * intensity);
+            lines.push(format!("Kd {}", mtl_vector(tint)));
+            lines.push(format!("map_Kd {}", textures.file(texture)?));
+            lines.push("illum 1".to_string());
+        },
+        MaterialDescription::VertexColor { tint, emission: intensity } => {
+            emission(&mut lines, tint * intensity);
+            lines.push(format!("Kd {}", mtl_vector(tint)));
+            lines.push("illum 1".to_string());
+        },
+        MaterialDescription::Pbr { base_color, texture, metallic, roughness, emission: color } => {
+            emission(&mut lines, color);
+            lines.push(format!("Kd {}", mtl_vector(base_color)));
+            if let Some(texture) = texture {
+                lines.push(format!("map_Kd {}", textures.file(texture)?));
+            }
+            // Pm and Pr are the PBR extension of the format
+            lines.push(format!("Pm {}", metallic));
+            lines.push(format!("Pr {}", roughness));
+            if metallic >= 0.5 {
+                lines.push(format!("Ks {}", mtl_vector(base_color)));
+                lines.push(format!("Ns {}", (1. - roughness) * 1000.));
+                lines.push("illum 3".to_string());
+            } else {
+                lines.push("illum 1".to_string());
+            }
+        }
+    }
+    Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+// Describes a material as the keys of a scene file's material section
+fn toml_material(material: &dyn Material, two_sided: bool, textures: &mut TextureFiles) -> Result<String, ExportError> {
+    let mut lines = match material.description() {
+        // Whether back faces are hit comes from the geometry instead
+        MaterialDescription::TwoSided(material) => return toml_material(material, two_sided, textures),
+        MaterialDescription::Diffuse { color, emission } => vec![
+            "type = \"diffuse\"".to_string(),
+            format!("color = {}", toml_vector(color)),
+            format!("emission = {:?}", emission)
+        ],
+        MaterialDescription::Reflective { color, emission, glossiness } => vec![
+            "type = \"reflective\"".to_string(),
+            format!("color = {}", toml_vector(color)),
+            format!("emission = {:?}", emission),
+            format!("glossiness = {:?}", glossiness)
+        ],
+        MaterialDescription::Dielectric { color, refraction_index } => vec![
+            "type = \"dielectric\"".to_string(),
+            format!("color = {}", toml_vector(color)),
+            format!("refraction_index = {:?}", refraction_index)
+        ],
+        MaterialDescription::Textured { texture, tint, emission } => vec![
+            "type = \"textured\"".to_string(),
+            format!("texture = \"{}\"", textures.file(texture)?),
+            format!("color = {}", toml_vector(tint)),
+            format!("emission = {:?}", emission)
+        ],
+        MaterialDescription::VertexColor { tint, emission } => vec![
+            "type = \"vertex_color\"".to_string(),
+            format!("color = {}", toml_vector(tint)),
+            format!("emission = {:?}", emission)
+        ],
+        // Scene files have no metallic-roughness materials. Lights become
+        // diffuse, metals reflective and everything else textured or diffuse
+        MaterialDescription::Pbr { base_color, texture, metallic, roughness, emission } => {
+            let intensity = emission.x.max(emission.y).max(emission.z);
+            if intensity > 0. {
+                vec![
+                    "type = \"diffuse\"".to_string(),
+                    format!("color = {}", toml_vector(emission / intensity)),
+                    format!("emission = {:?}", intensity)
+                ]
+            } else if metallic >= 0.5 {
+                vec![
+                    "type = \"reflective\"".to_string(),
+                    format!("color = {}", toml_vector(base_color)),
+                    format!("glossiness = {:?}", 1. - roughness)
+                ]
+            } else if let Some(texture) = texture {
+                vec![
+                    "type = \"textured\"".to_string(),
+                    format!("texture = \"{}\"", textures.file(texture)?),
+                    format!("color = {}", toml_vector(base_color))
+                ]
+            } else {
+                vec![
+                    "type = \"diffuse\"".to_string(),
+                    format!("color = {}", toml_vector(base_color))
+                ]
+            }
+        }
+    };
+    if two_sided {
+        lines.push("two_sided = true".to_string());
+    }
+    Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+// Writes meshes into a single .obj file, each as an object of its own. Vertex
+// colors are written after the positions
+fn write_parts(path: &Path, parts: &[(String, &Part)], materials: &[String], mtllib: Option<&str>) -> Result<(), ExportError> {
+    write_file(path, |file| {
+        writeln!(file, "# Exported by caracol")?;
+        if let Some(mtllib) = mtllib {
+            writeln!(file, "mtllib {}", mtllib)?;
+        }
+
+        // .obj indexes are 1-based and count across the whole file
+        let (mut vertex_offset, mut normal_offset, mut uv_offset) = (1, 1, 1);
+        for (name, part) in parts {
+            writeln!(file, "o {}", name)?;
+            for (i, vertex) in part.vertices.iter().enumerate() {
+                match part.colors.get(i) {
+                    Some(color) => writeln!(file, "v {} {}", mtl_vector(*vertex), mtl_vector(*color))?,
+                    None => writeln!(file, "v {}", mtl_vector(*vertex))?
+                }
+            }
+            for (u, v) in &part.uvs {
+                writeln!(file, "vt {} {}", u, v)?;
+            }
+            for normal in &part.normals {
+                writeln!(file, "vn {}", mtl_vector(*normal))?;
+            }
+
+            let mut material = None;
+            for face in &part.faces {
+                if material != Some(face.material) {
+                    material = Some(face.material);
+                    writeln!(file, "usemtl {}", materials[face.material as usize])?;
+                }
+
+                let corners: Vec<String> = (0..3).map(|j| {
+                    let vertex = face.vertices[j] + vertex_offset;
+                    match (face.uvs, face.normals) {
+                        (Some(uvs), Some(normals)) => format!("{}/{}/{}", vertex, uvs[j] + uv_offset, normals[j] + normal_offset),
+                        (Some(uvs), None) => format!("{}/{}", vertex, uvs[j] + uv_offset),
+                        (None, Some(normals)) => format!("{}//{}", vertex, normals[j] + normal_offset),
+                        (None, None) => vertex.to_string()
+                    }
+                }).collect();
+                writeln!(file, "f {}", corners.join(" "))?;
+            }
+
+            vertex_offset += part.vertices.len() as u32;
+            normal_offset += part.normals.len() as u32;
+            uv_offset += part.uvs.len() as u32;
+        }
+        Ok(())
+    })
+}
+
+fn write_file(path: &Path, contents: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> Result<(), ExportError> {
+    File::create(path)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            contents(&mut writer)?;
+            writer.flush()
+        })
+        .map_err(|error| ExportError::Io { path: path.to_path_buf(), error })
+}
+
+// Returns the path of a file next to path named after it, such as
+// scene_mesh0.obj for scene.toml
+fn sibling(path: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_{}.{}", stem, suffix, extension))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn mtl_vector(v: Vector) -> String {
+    format!("{} {} {}", v.x, v.y, v.z)
+}
+
+// Debug formatting always writes floats, which TOML needs for large numbers
+fn toml_vector(v: Vector) -> String {
+    format!("[{:?}, {:?}, {:?}]", v.x, v.y, v.z)
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use std::sync::Arc;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use caracol::config::{BvhSettings, PARALLEL_BUILD_SIZE};
+use caracol::geometry::hitbox::HitBox;
+use caracol::geometry::ray::Ray;
+use caracol::geometry::vector::Vector;
+use caracol::scene::bvh::{build_bvh_with, BvhStats, BvhTree};
+#[cfg(feature = "wide-bvh")]
+use caracol::scene::bvh::{traverse, ItemHit};
+#[cfg(feature = "wide-bvh")]
+use caracol::scene::wide_bvh::{collapse, traverse_wide};
+use caracol::scene::elements::{SceneElement, Sphere, Triangle};
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::scene::mesh::Mesh;
+use caracol::utils::reader::read_obj;
+use common::assert_same_hits;
+
+const SPATIAL: BvhSettings = BvhSettings {
+    traversal_cost: 1.,
+    intersection_cost: 1.,
+    max_leaf_size: 8,
+    spatial_splits: true,
+    max_split_growth: 2.,
+    rebuild_threshold: 1.5
+};
+
+#[test]
+fn hitbox_areas_are_surface_areas() {
+    let hitbox = HitBox::new(Vector::new(1., 1., 1.), Vector::new(2., 3., 4.));
+    assert_eq!(hitbox.area(), 22.);
+}
+
+// Four spheres of radius 1 in a row, 10 units apart, split in halves and then
+// into single spheres
+#[test]
+fn bvh_stats_describe_the_tree() {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let spheres: Vec<Arc<dyn SceneElement>> = (0..4)
+        .map(|i| Arc::new(Sphere::new(Vector::new(i as f64 * 10., 0., 0.), 1., material.clone())) as Arc<dyn SceneElement>)
+        .collect();
+    let settings = BvhSettings { max_leaf_size: 1, ..BvhSettings::default() };
+    let mut stats = BvhStats::default();
+    build_bvh_with(spheres, &settings).bvh_stats(&mut stats, 0);
+
+    assert_eq!((stats.nodes, stats.leaves, stats.depth), (7, 4, 3));
+    assert_eq!(stats.leaf_sizes, [0, 4]);
+    assert_eq!(stats.elements(), 4);
+    // The root is 32x2x2, its children 12x2x2 and the leaves 2x2x2
+    let (root, inner, leaves) = (264., 264. + 2. * 104., 4. * 24.);
+    let cost = (settings.traversal_cost * inner + settings.intersection_cost * leaves) / root;
+    assert!((stats.sah_cost(&settings) - cost).abs() < 1e-12, "{} isn't {}", stats.sah_cost(&settings), cost);
+}
+
+// Triangles scattered in a 10 unit cube
+fn random_triangles(count: usize) -> Vec<Arc<dyn SceneElement>> {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let mut rng = StdRng::seed_from_u64(2);
+    let mut point = || Vector::new(rng.random_range(0. ..10.), rng.random_range(0. ..10.), rng.random_range(0. ..10.));
+    (0..count).map(|_| {
+        let a = point();
+        let (b, c) = (a + (point() - a) * 0.1, a + (point() - a) * 0.1);
+        Arc::new(Triangle::new(a, b, c, material.clone())) as Arc<dyn SceneElement>
+    }).collect()
+}
+
+// Large enough to be built in parallel, the BVH must find the same hits as
+// testing every element
+#[test]
+fn bvhs_find_the_closest_hit() {
+    let triangles = random_triangles(2 * PARALLEL_BUILD_SIZE);
+    let bvh = build_bvh_with(triangles.clone(), &BvhSettings::default());
+
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut hits = 0;
+    for _ in 0..200 {
+        let origin = Vector::new(rng.random_range(-5. ..15.), rng.random_range(-5. ..15.), -5.);
+        let target = Vector::new(rng.random_range(0. ..10.), rng.random_range(0. ..10.), rng.random_range(0. ..10.));
+        let ray = Ray::new(origin, target - origin);
+
+        let closest = triangles.iter()
+            .filter_map(|triangle| Some(triangle.collide(&ray, f64::INFINITY)?.distance))
+            .min_by(f64::total_cmp);
+        assert_eq!(bvh.collide(&ray, f64::INFINITY).map(|hit| hit.distance), closest);
+        assert_eq!(bvh.any_hit(&ray, 1.), closest.is_some_and(|distance| distance < 1.));
+        hits += closest.is_some() as usize;
+    }
+    assert!(hits > 20, "only {} rays hit anything", hits);
+}
+
+#[test]
+fn spatial_splits_find_the_same_hits_in_meshes() {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let mesh = read_obj("src/assets/grass.obj", material).unwrap();
+    let split = Mesh::with_settings(mesh.buffers().clone(), mesh.faces().to_vec(), mesh.materials().to_vec(), &SPATIAL);
+
+    let stats = split.bvh_stats();
+    let references: usize = stats.leaf_sizes.iter().enumerate().map(|(size, count)| size * count).sum();
+    assert!(references > mesh.faces().len(), "no face was split");
+    assert_same_hits(&mesh, &split);
+}
+
+#[test]
+fn spatial_splits_find_the_same_hits_in_bvhs() {
+    let triangles = random_triangles(500);
+    let plain = build_bvh_with(triangles.clone(), &BvhSettings::default());
+    let split = build_bvh_with(triangles, &SPATIAL);
+    assert_same_hits(plain.as_ref(), split.as_ref());
+}
+
+// The 4-wide BVH collapsed from a binary one must find the same closest hits
+// and reach the same items as the binary one
+#[cfg(feature = "wide-bvh")]
+#[test]
+fn wide_bvhs_find_the_same_hits_as_binary_bvhs() {
+    let triangles = random_triangles(2000);
+    let hitboxes: Vec<HitBox> = triangles.iter().map(|triangle| *triangle.hitbox()).collect();
+
+    for settings in [BvhSettings::default(), SPATIAL] {
+        let tree = BvhTree::build(&hitboxes, &settings);
+        let (nodes, indices) = (tree.nodes(), tree.indices());
+        let wide_nodes = collapse(nodes);
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let origin = Vector::new(rng.random_range(-5. ..15.), rng.random_range(-5. ..15.), -5.);
+            let target = Vector::new(rng.random_range(0. ..10.), rng.random_range(0. ..10.), rng.random_range(0. ..10.));
+            let ray = Ray::new(origin, target - origin);
+            let collide = |i: usize, distance: f64| triangles[indices[i] as usize].collide(&ray, distance);
+
+            let closest = |i, distance| collide(i, distance).map_or(ItemHit::Miss, |info| ItemHit::Closer(info.distance));
+            let distance = traverse(nodes, &ray, f64::INFINITY, closest);
+            assert_eq!(traverse_wide(&wide_nodes, &ray, f64::INFINITY, closest), distance);
+
+            let (mut binary, mut wide) = (Vec::new(), Vec::new());
+            traverse(nodes, &ray, f64::INFINITY, |i, _| {
+                if collide(i, f64::INFINITY).is_some() { binary.push(indices[i]) }
+                ItemHit::Miss
+            });
+            traverse_wide(&wide_nodes, &ray, f64::INFINITY, |i, _| {
+                if collide(i, f64::INFINITY).is_some() { wide.push(indices[i]) }
+                ItemHit::Miss
+            });
+            binary.sort_unstable();
+            wide.sort_unstable();
+            assert_eq!(binary, wide);
+            hits += distance.is_finite() as usize;
+        }
+        assert!(hits > 50, "only {} rays hit anything", hits);
+    }
+}
+
+fn tree_bytes(tree: &BvhTree) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    tree.write(&mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn bvhs_are_read_back_as_written() {
+    let hitboxes: Vec<HitBox> = random_triangles(1000).iter().map(|triangle| *triangle.hitbox()).collect();
+    for settings in [BvhSettings::default(), SPATIAL] {
+        let tree = BvhTree::build(&hitboxes, &settings);
+        let bytes = tree_bytes(&tree);
+        // Nodes take 32 bytes, after the number of nodes and indices
+        assert_eq!(bytes.len(), 16 + 32 * tree.nodes().len() + 4 * tree.indices().len());
+
+        let read = BvhTree::read(&mut bytes.as_slice(), hitboxes.len()).unwrap();
+        assert_eq!(tree_bytes(&read), bytes);
+        assert_eq!(read.indices(), tree.indices());
+    }
+}
+
+#[test]
+fn corrupted_bvhs_are_errors() {
+    let hitboxes: Vec<HitBox> = random_triangles(100).iter().map(|triangle| *triangle.hitbox()).collect();
+    let bytes = tree_bytes(&BvhTree::build(&hitboxes, &BvhSettings::default()));
+    let read = |bytes: &[u8], items: usize| BvhTree::read(&mut &bytes[..], items).err().expect("the corrupted BVH was read");
+
+    assert_eq!(read(&bytes[..bytes.len() - 1], hitboxes.len()).kind(), std::io::ErrorKind::UnexpectedEof);
+    // Fewer items than the leaves refer to
+    assert_eq!(read(&bytes, 10).kind(), std::io::ErrorKind::InvalidData);
+    // The root's right child pointing back at the root
+    let mut looping = bytes.clone();
+    looping[16 + 24..16 + 28].copy_from_slice(&0u32.to_le_bytes());
+    assert_eq!(read(&looping, hitboxes.len()).kind(), std::io::ErrorKind::InvalidData);
+    // More nodes than there are
+    let mut counted = bytes.clone();
+    counted[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(BvhTree::read(&mut counted.as_slice(), hitboxes.len()).is_err());
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use caracol::geometry::vector::Vector;
+use caracol::scene::cache::set_cache_directory;
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::utils::reader::read_obj;
+use common::assert_same_hits;
+
+#[test]
+fn cached_meshes_are_read_back_unchanged() {
+    let directory = std::env::temp_dir().join(format!("caracol-cache-test-{}", std::process::id()));
+    set_cache_directory(Some(directory.clone()));
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+
+    let built = read_obj("src/assets/snail.obj", material.clone()).unwrap();
+    let files: Vec<_> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(files.len(), 1, "the mesh wasn't cached");
+
+    let cached = read_obj("src/assets/snail.obj", material.clone()).unwrap();
+    assert_eq!(built.faces().len(), cached.faces().len());
+    let positions = |vertices: &[Vector]| vertices.iter().map(|v| [v.x, v.y, v.z]).collect::<Vec<_>>();
+    assert_eq!(positions(&built.buffers().vertices), positions(&cached.buffers().vertices));
+    assert_eq!(built.bvh_stats().nodes, cached.bvh_stats().nodes);
+    assert_same_hits(&built, &cached);
+
+    // Broken cache files are ignored and written again
+    fs::write(&files[0], b"CRCLMESH broken").unwrap();
+    let rebuilt = read_obj("src/assets/snail.obj", material).unwrap();
+    assert_same_hits(&built, &rebuilt);
+    assert!(fs::metadata(&files[0]).unwrap().len() > 15);
+
+    set_cache_directory(None);
+    fs::remove_dir_all(&directory).unwrap();
+}
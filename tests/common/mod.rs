@@ -0,0 +1,46 @@
+// Helpers shared by the tests. Not every test uses all of them
+#![allow(dead_code)]
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use caracol::geometry::ray::Ray;
+use caracol::scene::elements::SceneElement;
+use caracol::geometry::vector::Vector;
+
+// Rays from around the hitbox of element towards points inside it
+fn rays(element: &dyn SceneElement, count: usize) -> Vec<Ray> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let hitbox = element.hitbox();
+    let (start, size) = (hitbox.start(), hitbox.end() - hitbox.start());
+    let mut point = |margin: f64| Vector::new(
+        start.x + size.x * rng.random_range(-margin..1. + margin),
+        start.y + size.y * rng.random_range(-margin..1. + margin),
+        start.z + size.z * rng.random_range(-margin..1. + margin)
+    );
+    (0..count).map(|_| {
+        let origin = point(1.);
+        Ray::new(origin, point(0.) - origin)
+    }).collect()
+}
+
+// Checks that both elements find the same closest hits, and agree on whether
+// rays are blocked
+pub fn assert_same_hits(expected: &dyn SceneElement, actual: &dyn SceneElement) {
+    assert_close_hits(expected, actual, 0.);
+}
+
+// Like assert_same_hits, but distances may differ by tolerance times the
+// distance, for elements placed with different rounding errors
+pub fn assert_close_hits(expected: &dyn SceneElement, actual: &dyn SceneElement, tolerance: f64) {
+    let mut hits = 0;
+    for ray in rays(expected, 2000) {
+        let distance = |element: &dyn SceneElement| element.collide(&ray, f64::INFINITY).map(|hit| hit.distance);
+        match (distance(expected), distance(actual)) {
+            (Some(a), Some(b)) => assert!((a - b).abs() <= tolerance * a, "hit at {} instead of {}", b, a),
+            (a, b) => assert_eq!(a, b)
+        }
+        assert_eq!(expected.any_hit(&ray, 1.), actual.any_hit(&ray, 1.));
+        hits += distance(expected).is_some() as usize;
+    }
+    assert!(hits > 100, "only {} rays hit anything", hits);
+}
@@ -0,0 +1,310 @@
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use image::{Rgb, RgbImage};
+use caracol::geometry::ray::Ray;
+use caracol::geometry::vector::Vector;
+use caracol::scene::elements::{CollisionInfo, SceneElement};
+use caracol::scene::loader::{load_scene, parse_scene, SceneError};
+use caracol::scene::materials::{DiffuseMaterial, Material, MaterialDescription};
+use caracol::scene::mesh::Mesh;
+use caracol::scene::pbrt_loader::read_pbrt;
+use caracol::scene::tlas::Tlas;
+use caracol::utils::reader::{read_obj, read_ply, read_stl, MtlMaterial, ObjError};
+use caracol::utils::writer::write_scene;
+use common::assert_same_hits;
+
+// A directory of its own for each test, since they run in parallel
+fn directory(test: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("caracol-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+// The line of the error in a scene file
+fn error_line(source: &str) -> usize {
+    match parse_scene(source, Path::new("scene.toml")) {
+        Err(SceneError::Parse { line, .. }) => line,
+        Err(error) => panic!("unexpected error {}", error),
+        Ok(_) => panic!("the scene was loaded")
+    }
+}
+
+#[test]
+fn scene_errors_report_their_line() {
+    assert_eq!(error_line("[render]\nwidth = 100\nrays_per_pixel = 0\n"), 3);
+    assert_eq!(error_line("[render]\n\nheight = 0\n"), 3);
+    assert_eq!(error_line("[camera]\nfov = 60\n\n[camera]\n"), 4);
+    assert_eq!(error_line("[materials.red]\ntype = \"diffuse\"\ncolor = [1, 0, 0]\n\n\
+        [[spheres]]\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"blue\"\n"), 8);
+}
+
+#[test]
+fn pbrt_files_that_include_themselves_are_errors() {
+    let directory = directory("pbrt-include");
+    fs::write(directory.join("a.pbrt"), "WorldBegin\nInclude \"b.pbrt\"\n").unwrap();
+    fs::write(directory.join("b.pbrt"), "Include \"a.pbrt\"\n").unwrap();
+
+    let error = read_pbrt(directory.join("a.pbrt")).err().expect("the include cycle was loaded");
+    assert!(error.to_string().ends_with("a.pbrt includes itself"), "{}", error);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn ply_faces_with_negative_indexes_are_errors() {
+    let directory = directory("ply-negative");
+    let path = directory.join("negative.ply");
+    fs::write(&path, "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+        element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 1 0\n3 0 -1 2\n").unwrap();
+
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let error = read_ply(path.to_str().unwrap(), material).err().expect("the negative index was accepted");
+    assert!(error.to_string().contains("negative vertex index"), "{}", error);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+// The kind of material at a hit and the color it has there
+fn shading(material: &dyn Material, info: &CollisionInfo) -> String {
+    match material.description() {
+        MaterialDescription::Diffuse { color, emission } => format!("diffuse {:?} {:?}", [color.x, color.y, color.z], emission),
+        MaterialDescription::Textured { texture, tint, emission } => {
+            let color = texture.sample(info.uv);
+            format!("textured {:?} {:?} {:?}", [color.x, color.y, color.z], [tint.x, tint.y, tint.z], emission)
+        },
+        MaterialDescription::TwoSided(material) => format!("two sided {}", shading(material, info)),
+        _ => panic!("the scene has no other materials")
+    }
+}
+
+#[test]
+fn exported_scenes_are_loaded_back_the_same() {
+    let directory = directory("export");
+    RgbImage::from_fn(2, 2, |x, y| Rgb([255 * x as u8, 255 * y as u8, 128])).save(directory.join("checker.png")).unwrap();
+    // Two walls with a material each, the second one textured
+    fs::write(directory.join("walls.mtl"), "newmtl red\nKd 0.8 0.1 0.1\nnewmtl blue\nKd 0.1 0.1 0.8\n").unwrap();
+    fs::write(directory.join("walls.obj"), "mtllib walls.mtl\n\
+        v -2 0 0\nv 0 0 0\nv 0 2 0\nv -2 2 0\nv 2 0 0\nv 2 2 0\n\
+        vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+        usemtl red\nf 1/1 2/2 3/3\nf 1/1 3/3 4/4\n\
+        usemtl blue\nf 2/1 5/2 6/3\nf 2/1 6/3 3/4\n").unwrap();
+    fs::write(directory.join("leaf.obj"), "v -1.5 0.5 -0.5\nv -0.5 0.5 0.5\nv -0.5 1.5 0.5\nv -1.5 1.5 -0.5\nf 1 2 3\nf 1 3 4\n").unwrap();
+    fs::write(directory.join("sail.obj"), "v -0.2 0.2 0.3\nv 0.4 0.2 -0.3\nv 0.4 1.8 -0.3\nv -0.2 1.8 0.3\nf 1 2 3\nf 1 3 4\n").unwrap();
+    // Transforms would be applied to the exported geometry, which moves hits by
+    // rounding errors, so the geometry is placed in the files instead
+    fs::write(directory.join("scene.toml"), r#"
+        [materials.green]
+        type = "diffuse"
+        color = [0.1, 0.7, 0.2]
+
+        [materials.checker]
+        type = "textured"
+        color = [1, 0.5, 1]
+        texture = "checker.png"
+
+        [materials.yellow]
+        type = "diffuse"
+        color = [0.9, 0.8, 0.1]
+
+        [materials.sail]
+        type = "diffuse"
+        color = [0.9, 0.9, 0.9]
+        two_sided = true
+
+        [[meshes]]
+        file = "walls.obj"
+        use_mtl = true
+        material_overrides = { blue = "checker" }
+
+        [[meshes]]
+        file = "sail.obj"
+        material = "sail"
+
+        [objects.leaf]
+        file = "leaf.obj"
+        material = "green"
+
+        [[instances]]
+        object = "leaf"
+        material = "yellow"
+
+        [[spheres]]
+        center = [1, 1, 0]
+        radius = 0.5
+        material = "green"
+    "#).unwrap();
+
+    let scene = load_scene(directory.join("scene.toml")).unwrap();
+    let exported = directory.join("exported.toml");
+    write_scene(exported.to_str().unwrap(), &scene).unwrap();
+    let reloaded = load_scene(&exported).unwrap();
+
+    let (expected, actual) = (Tlas::new(scene.elements), Tlas::new(reloaded.elements));
+    assert_same_hits(&expected, &actual);
+
+    // Every material must be hit somewhere and come back the same
+    let mut shadings: Vec<String> = Vec::new();
+    for step in 0..400 {
+        let (x, y) = ((step % 20) as f64 * 0.2 - 2., (step / 20) as f64 * 0.1);
+        for z in [5., -5.] {
+            let ray = Ray::new(Vector::new(x, y, z), Vector::new(0.1, 0.05, -z));
+            let hits = [&expected, &actual].map(|scene| scene.collide(&ray, f64::INFINITY).map(|info| shading(info.material.as_ref(), &info)));
+            assert_eq!(hits[0], hits[1]);
+            shadings.extend(hits[0].clone());
+        }
+    }
+    // The walls, the instance, the sphere, the textured wall and the sail
+    for material in ["diffuse [0.8, 0.1, 0.1]", "diffuse [0.9, 0.8, 0.1]", "diffuse [0.1, 0.7, 0.2]", "textured", "two sided"] {
+        assert!(shadings.iter().any(|shading| shading.starts_with(material)), "{} was never hit", material);
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn reflective_mtl_materials_without_ks_keep_their_color() {
+    let color = |material: MtlMaterial| match material.to_material().description() {
+        MaterialDescription::Reflective { color, .. } => [color.x, color.y, color.z],
+        _ => panic!("illum 3 didn't make a reflective material")
+    };
+    let diffuse = Vector::new(0.2, 0.4, 0.6);
+    assert_eq!(color(MtlMaterial { diffuse, illumination: 3, ..MtlMaterial::default() }), [0.2, 0.4, 0.6]);
+    assert_eq!(color(MtlMaterial { diffuse, specular: Vector::uniform(0.9), illumination: 3, ..MtlMaterial::default() }), [0.9; 3]);
+}
+
+#[test]
+fn obj_files_accept_relative_indexes_and_continued_lines() {
+    let directory = directory("obj-indexes");
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let read = |name: &str, source: &str| {
+        let path = directory.join(name);
+        fs::write(&path, source).unwrap();
+        read_obj(path.to_str().unwrap(), material.clone()).unwrap()
+    };
+
+    let absolute = read("absolute.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 1\nf 1 2 3\nf 2 4 3\n");
+    let relative = read("relative.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 1 \\\n1 1\nf -3 \\\n  -1 -2\n");
+    assert_eq!(relative.faces().len(), 2);
+    assert_eq!(relative.buffers().vertices.len(), 4);
+    assert_same_hits(&absolute, &relative);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn obj_indexes_out_of_range_are_errors() {
+    let directory = directory("obj-range");
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    for face in ["f 1 2 4", "f 1 2 -4", "f 0 1 2", "f 1/2 2/2 3/2"] {
+        let path = directory.join("range.obj");
+        fs::write(&path, format!("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\n{}\n", face)).unwrap();
+        match read_obj(path.to_str().unwrap(), material.clone()) {
+            Err(ObjError::Parse { line, message, .. }) => {
+                assert_eq!(line, 5, "{}", message);
+                assert!(message.contains("out of range"), "{}", message);
+            },
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("`{}` was loaded", face)
+        }
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+// A unit cube, with its faces towards the outside
+fn cube() -> Vec<[Vector; 3]> {
+    let mut triangles = Vec::new();
+    for axis in 0..3 {
+        for sign in [-1., 1.] {
+            let unit = |i: usize, length: f64| {
+                let mut coordinates = [0.; 3];
+                coordinates[i % 3] = length;
+                Vector::new(coordinates[0], coordinates[1], coordinates[2])
+            };
+            let (normal, u, v) = (unit(axis, sign), unit(axis + 1, 0.5), unit(axis + 2, 0.5 * sign));
+            let center = Vector::uniform(0.5) + normal * 0.5;
+            let [a, b, c, d] = [center - u - v, center + u - v, center + u + v, center - u + v];
+            triangles.extend([[a, b, c], [a, c, d]]);
+        }
+    }
+    triangles
+}
+
+fn write_stl(path: &Path, triangles: &[[Vector; 3]], binary: bool) {
+    if binary {
+        let mut bytes = vec![0; 80];
+        bytes.extend((triangles.len() as u32).to_le_bytes());
+        for triangle in triangles {
+            bytes.extend([0; 12]);
+            for vertex in triangle {
+                for coordinate in [vertex.x, vertex.y, vertex.z] {
+                    bytes.extend((coordinate as f32).to_le_bytes());
+                }
+            }
+            bytes.extend([0; 2]);
+        }
+        fs::write(path, bytes).unwrap();
+        return;
+    }
+
+    let mut text = "solid test\n".to_string();
+    for triangle in triangles {
+        text += "facet normal 0 0 0\nouter loop\n";
+        for vertex in triangle {
+            text += &format!("vertex {} {} {}\n", vertex.x, vertex.y, vertex.z);
+        }
+        text += "endloop\nendfacet\n";
+    }
+    fs::write(path, text + "endsolid test\n").unwrap();
+}
+
+fn arrays(vectors: &[Vector]) -> Vec<[f64; 3]> {
+    vectors.iter().map(|v| [v.x, v.y, v.z]).collect()
+}
+
+#[test]
+fn ascii_and_binary_stl_files_load_the_same_mesh() {
+    let directory = directory("stl-formats");
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let read = |name: &str, binary: bool| {
+        let path = directory.join(name);
+        write_stl(&path, &cube(), binary);
+        read_stl(path.to_str().unwrap(), material.clone()).unwrap()
+    };
+
+    let (ascii, binary) = (read("ascii.stl", false), read("binary.stl", true));
+    assert_eq!(arrays(&ascii.buffers().vertices), arrays(&binary.buffers().vertices));
+    assert_eq!(arrays(&ascii.buffers().normals), arrays(&binary.buffers().normals));
+    let faces = |mesh: &Mesh| mesh.faces().iter().map(|face| (face.vertices, face.normals)).collect::<Vec<_>>();
+    assert_eq!(faces(&ascii), faces(&binary));
+    assert_same_hits(&ascii, &binary);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn stl_vertices_are_merged_and_only_shallow_edges_are_smoothed() {
+    let directory = directory("stl-smoothing");
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let read = |triangles: &[[Vector; 3]]| {
+        let path = directory.join("mesh.stl");
+        write_stl(&path, triangles, false);
+        read_stl(path.to_str().unwrap(), material.clone()).unwrap()
+    };
+
+    // The edges of a cube are sharper than STL_SMOOTHING_ANGLE, so each
+    // corner has the normal of each of its three faces
+    let cube = read(&cube());
+    assert_eq!(cube.buffers().vertices.len(), 8);
+    assert_eq!(cube.buffers().normals.len(), 24);
+    for normal in &cube.buffers().normals {
+        assert_eq!([normal.x, normal.y, normal.z].map(f64::abs).iter().sum::<f64>(), 1., "{} isn't a face normal", normal);
+    }
+
+    // Two slopes meeting at a ridge about 11 degrees wide, so each vertex has
+    // a single normal, and the ridge gets the average of both slopes
+    let [a, b, c, d, e, f] = [[0., 0., 0.], [2., 0., 0.], [2., 1., 0.1], [0., 1., 0.1], [2., 2., 0.], [0., 2., 0.]]
+        .map(|[x, y, z]| Vector::new(x, y, z));
+    let ridge = read(&[[a, b, c], [a, c, d], [c, e, f], [c, f, d]]);
+    assert_eq!(ridge.buffers().vertices.len(), 6);
+    assert_eq!(ridge.buffers().normals.len(), 6);
+    assert!(ridge.buffers().normals.iter().any(|normal| normal.y.abs() < 1e-12 && normal.z > 0.));
+    fs::remove_dir_all(&directory).unwrap();
+}
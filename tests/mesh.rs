@@ -0,0 +1,65 @@
+mod common;
+
+use std::sync::Arc;
+use caracol::config::BvhSettings;
+use caracol::geometry::vector::Vector;
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::scene::mesh::{Mesh, MeshBuffers};
+use caracol::utils::reader::read_obj;
+use common::assert_same_hits;
+
+// The buffers of mesh with every vertex moved by offset
+fn moved(mesh: &Mesh, offset: impl Fn(&Vector) -> Vector) -> Arc<MeshBuffers> {
+    let buffers = mesh.buffers();
+    Arc::new(MeshBuffers {
+        vertices: buffers.vertices.iter().map(|vertex| vertex + offset(vertex)).collect(),
+        normals: buffers.normals.clone(),
+        uvs: buffers.uvs.clone(),
+        colors: buffers.colors.clone()
+    })
+}
+
+fn snail() -> Mesh {
+    read_obj("src/assets/snail.obj", Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.))).unwrap()
+}
+
+// A deformed mesh must be hit like a mesh built from scratch with the same
+// vertices, whether its BVH was refitted or built again
+#[test]
+fn deformed_meshes_are_hit_like_new_ones() {
+    let mesh = snail();
+    let wave = |vertex: &Vector| Vector::new(0., 0.1 * (vertex.x * 3.).sin(), 0.);
+    let scatter = |vertex: &Vector| Vector::new((vertex.y * 50.).sin(), (vertex.z * 50.).sin(), (vertex.x * 50.).sin());
+
+    for offset in [&wave as &dyn Fn(&Vector) -> Vector, &scatter] {
+        let buffers = moved(&mesh, offset);
+        let deformed = mesh.deform(buffers.clone()).unwrap();
+        let built = Mesh::new(buffers, mesh.faces().to_vec(), mesh.materials().to_vec());
+        assert_same_hits(&built, &deformed);
+    }
+}
+
+#[test]
+fn deformed_meshes_with_spatial_splits_are_hit_like_new_ones() {
+    let settings = BvhSettings { spatial_splits: true, max_split_growth: 2., ..BvhSettings::default() };
+    let mesh = snail();
+    let mesh = Mesh::with_settings(mesh.buffers().clone(), mesh.faces().to_vec(), mesh.materials().to_vec(), &settings);
+
+    let buffers = moved(&mesh, |vertex| Vector::new(0., 0.1 * (vertex.x * 3.).sin(), 0.));
+    let deformed = mesh.deform(buffers.clone()).unwrap();
+    let built = Mesh::new(buffers, mesh.faces().to_vec(), mesh.materials().to_vec());
+    assert_same_hits(&built, &deformed);
+}
+
+#[test]
+fn deforming_with_missing_vertices_fails() {
+    let mesh = snail();
+    let mut buffers = MeshBuffers {
+        vertices: mesh.buffers().vertices.clone(),
+        normals: mesh.buffers().normals.clone(),
+        uvs: mesh.buffers().uvs.clone(),
+        colors: Vec::new()
+    };
+    buffers.vertices.pop();
+    assert!(mesh.deform(Arc::new(buffers)).is_err());
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use caracol::config::{create_scene, CameraSettings, RenderSettings};
+use caracol::geometry::transform::Transform;
+use caracol::geometry::vector::Vector;
+use caracol::renderer::raytracer::{Raytracer, Traversal};
+use caracol::scene::elements::{Instance, SceneElement, Sphere, Transformed};
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::utils::reader::read_obj;
+
+// The default scene with elements of every kind that traces packets its own
+// way: meshes, spheres, transformed elements and instances
+fn scene() -> Vec<Arc<dyn SceneElement>> {
+    let material = Arc::new(DiffuseMaterial::new(Vector::new(0.2, 0.4, 0.8), 0.));
+    let rocks: Arc<dyn SceneElement> = Arc::new(read_obj("src/assets/rocks.obj", material.clone()).unwrap());
+    let sphere: Arc<dyn SceneElement> = Arc::new(Sphere::new(Vector::new(-1.5, 1., 0.), 0.5, material.clone()));
+
+    let mut elements = create_scene().unwrap();
+    elements.push(sphere.clone());
+    elements.push(Arc::new(Transformed::new(sphere, Transform::translation(Vector::new(3., 0., 0.)) * Transform::scaling(Vector::new(1., 2., 1.)))));
+    elements.push(Arc::new(Instance::new(rocks, Transform::translation(Vector::new(1.5, 0.5, 1.)))
+        .with_material(Arc::new(DiffuseMaterial::new(Vector::uniform(0.9), 0.)))));
+    elements
+}
+
+#[test]
+fn packets_render_the_same_image_as_single_rays() {
+    // Neither dimension is a multiple of the packet size, so that partial
+    // packets are traced too
+    let settings = RenderSettings { width: 50, height: 30, rays_per_pixel: 2, bounces: 2, seed: Some(7), ..RenderSettings::default() };
+    let mut raytracer = Raytracer::new(scene(), CameraSettings::default(), settings);
+
+    raytracer.set_traversal(Traversal::Single);
+    let single = raytracer.render(|_| ());
+    raytracer.set_traversal(Traversal::Packets);
+    let packets = raytracer.render(|_| ());
+
+    assert!(single.pixels().any(|pixel| pixel.0 != [0; 3]), "the image is black");
+    let differing = single.pixels().zip(packets.pixels()).filter(|(a, b)| a != b).count();
+    assert_eq!(differing, 0, "{} pixels differ", differing);
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use std::sync::Arc;
+use caracol::config::BvhSettings;
+use caracol::geometry::ray::Ray;
+use caracol::geometry::transform::Transform;
+use caracol::geometry::vector::Vector;
+use caracol::scene::bvh::{build_bvh, build_bvh_with, BvhStats};
+use caracol::scene::elements::{SceneElement, Sphere, Transformed, Triangle};
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::scene::tlas::Tlas;
+use caracol::utils::reader::read_obj;
+use common::assert_same_hits;
+
+// A lattice of long, thin, slanted triangles, half of them along x and half
+// along y. Only spatial splits can separate them
+fn lattice() -> Vec<Arc<dyn SceneElement>> {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    (0..256).map(|i| {
+        let (offset, z) = ((i / 2) as f64 * 0.08, (i % 16) as f64 * 0.3);
+        let [a, b, c] = if i % 2 == 0 {
+            [Vector::new(0., offset, z), Vector::new(10., offset, z + 3.), Vector::new(0., offset + 0.2, z)]
+        } else {
+            [Vector::new(offset, 0., z), Vector::new(offset + 0.2, 0., z), Vector::new(offset, 10., z + 3.)]
+        };
+        Arc::new(Triangle::new(a, b, c, material.clone())) as Arc<dyn SceneElement>
+    }).collect()
+}
+
+const SPLIT: BvhSettings = BvhSettings {
+    traversal_cost: 1.,
+    intersection_cost: 1.,
+    max_leaf_size: 1,
+    spatial_splits: true,
+    max_split_growth: 4.,
+    rebuild_threshold: 1.5
+};
+
+fn assert_split(element: &dyn SceneElement, count: usize) {
+    let mut stats = BvhStats::default();
+    element.bvh_stats(&mut stats, 0);
+    let references: usize = stats.leaf_sizes.iter().enumerate().map(|(size, count)| size * count).sum();
+    assert!(references > count, "no triangle was split");
+}
+
+// Every ray must hit each triangle of the lattice at most once, however many
+// leaves it is in
+fn assert_hits_once(scene: &Tlas, triangles: &[Arc<dyn SceneElement>]) {
+    for step in 0..1600 {
+        let (x, y) = ((step % 40) as f64 * 0.26 + 0.01, (step / 40) as f64 * 0.26 + 0.02);
+        let direction = Vector::new((step % 7) as f64 * 0.1 - 0.3, (step % 5) as f64 * 0.1 - 0.2, -1.);
+        let ray = Ray::new(Vector::new(x, y, 10.), direction);
+
+        let hits: Vec<f64> = scene.all_hits(&ray, f64::INFINITY).map(|hit| hit.distance).collect();
+        let mut expected: Vec<f64> = triangles.iter().filter_map(|triangle| Some(triangle.collide(&ray, f64::INFINITY)?.distance)).collect();
+        expected.sort_by(f64::total_cmp);
+        assert_eq!(hits, expected);
+    }
+}
+
+#[test]
+fn all_hits_reports_split_elements_once() {
+    let triangles = lattice();
+    let bvh = build_bvh_with(triangles.clone(), &SPLIT);
+    assert_split(bvh.as_ref(), triangles.len());
+    assert_hits_once(&Tlas::new(vec![bvh]), &triangles);
+}
+
+#[test]
+fn all_hits_reports_split_objects_once() {
+    let triangles = lattice();
+    let scene = Tlas::with_settings(triangles.clone(), &SPLIT);
+    assert_split(&scene, triangles.len());
+    assert_hits_once(&scene, &triangles);
+}
+
+// After objects are added, moved and removed, the top level must find the
+// same hits as a BVH over the objects where they are
+#[test]
+fn edited_scenes_are_hit_like_new_ones() {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let snail: Arc<dyn SceneElement> = Arc::new(read_obj("src/assets/snail.obj", material.clone()).unwrap());
+    let rocks: Arc<dyn SceneElement> = Arc::new(read_obj("src/assets/rocks.obj", material.clone()).unwrap());
+    let sphere: Arc<dyn SceneElement> = Arc::new(Sphere::new(Vector::new(0., 1., 0.), 0.5, material));
+
+    let mut scene = Tlas::new(vec![snail.clone(), sphere.clone()]);
+    let moved = Transform::translation(Vector::new(1., 0., 0.)) * Transform::rotation_y(0.5);
+    let rock = scene.add(rocks.clone(), moved);
+    let copy = scene.add(snail.clone(), Transform::scaling(Vector::uniform(0.5)));
+    let placed = Transform::translation(Vector::new(-1., 0.5, 0.));
+    assert_eq!(scene.set_transform(copy, placed).map(|transform| *transform.matrix()), Some(*Transform::scaling(Vector::uniform(0.5)).matrix()));
+    let sphere_id = scene.ids().nth(1).unwrap();
+    assert!(scene.remove(sphere_id).is_some());
+    assert!(scene.remove(sphere_id).is_none());
+    assert_eq!(scene.len(), 3);
+    assert!(scene.object(rock).is_some());
+
+    let expected = build_bvh(vec![
+        snail.clone(),
+        Arc::new(Transformed::new(rocks, moved)),
+        Arc::new(Transformed::new(snail, placed))
+    ]);
+    assert_same_hits(expected.as_ref(), &scene);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use std::sync::Arc;
+use caracol::geometry::transform::Transform;
+use caracol::geometry::vector::Vector;
+use caracol::scene::elements::Transformed;
+use caracol::scene::materials::DiffuseMaterial;
+use caracol::utils::reader::{read_obj, read_obj_transformed};
+use common::assert_close_hits;
+
+// Scales unevenly, rotates and moves, so that nothing cancels out
+fn transform() -> Transform {
+    Transform::translation(Vector::new(1., -2., 0.5))
+        * Transform::rotation_y(0.7)
+        * Transform::rotation_x(-0.3)
+        * Transform::scaling(Vector::new(2., 0.5, 1.5))
+}
+
+fn assert_close(a: Vector, b: Vector) {
+    assert!((a - b).magnitude() < 1e-9, "{} isn't {}", a, b);
+}
+
+#[test]
+fn inverses_undo_transforms() {
+    let transform = transform();
+    let point = Vector::new(0.3, -1.2, 4.);
+    assert_close(transform.inverse().point(&transform.point(&point)), point);
+    assert_close(transform.point(&transform.inverse().point(&point)), point);
+
+    // Inverting the matrix gives the inverse the transform was composed with
+    let inverted = Transform::new(*transform.matrix()).unwrap().inverse();
+    for (row, expected) in inverted.matrix().iter().zip(transform.inverse().matrix()) {
+        for (value, expected) in row.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-9, "{:?} isn't {:?}", inverted.matrix(), transform.inverse().matrix());
+        }
+    }
+
+    let flat = Transform::scaling(Vector::new(1., 0., 1.));
+    assert!(Transform::new(*flat.matrix()).is_none());
+}
+
+#[test]
+fn normals_stay_perpendicular_to_surfaces() {
+    let transform = transform();
+    let (u, v) = (Vector::new(1., 0.2, 0.), Vector::new(0., 1., 0.5));
+    let normal = transform.normal(&u.cross(&v));
+
+    assert!((normal.magnitude() - 1.).abs() < 1e-12);
+    assert!(normal.dot(&transform.vector(&u)).abs() < 1e-9);
+    assert!(normal.dot(&transform.vector(&v)).abs() < 1e-9);
+    // On the same side of the surface as before
+    assert!(normal.dot(&transform.vector(&u).cross(&transform.vector(&v))) > 0.);
+}
+
+#[test]
+fn transformed_meshes_are_hit_like_transformed_files() {
+    let material = Arc::new(DiffuseMaterial::new(Vector::uniform(0.5), 0.));
+    let transform = transform();
+    let moved = read_obj_transformed("src/assets/rocks.obj", material.clone(), &transform).unwrap();
+    let placed = Transformed::new(Arc::new(read_obj("src/assets/rocks.obj", material).unwrap()), transform);
+    assert_close_hits(&moved, &placed, 1e-9);
+}